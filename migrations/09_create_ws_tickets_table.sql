@@ -0,0 +1,16 @@
+-- Create ws_tickets table for short-lived, single-use WebSocket handshake tickets
+CREATE TABLE IF NOT EXISTS ws_tickets (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    ticket_hash VARCHAR(64) UNIQUE NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    used BOOLEAN DEFAULT false,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Create indexes for ticket lookups and cleanup
+CREATE INDEX IF NOT EXISTS idx_ws_tickets_user_id ON ws_tickets(user_id);
+CREATE INDEX IF NOT EXISTS idx_ws_tickets_expires_at ON ws_tickets(expires_at);
+
+-- Token hashes are now deterministic (SHA-256) so they can be looked up directly
+CREATE INDEX IF NOT EXISTS idx_tokens_token_hash ON auth_tokens(token_hash);
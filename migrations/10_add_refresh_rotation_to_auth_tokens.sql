@@ -0,0 +1,8 @@
+-- Track refresh-token rotation so a reused refresh token can be detected
+ALTER TABLE auth_tokens ADD COLUMN IF NOT EXISTS refresh_expires_at TIMESTAMPTZ;
+ALTER TABLE auth_tokens ADD COLUMN IF NOT EXISTS family_id VARCHAR(36);
+ALTER TABLE auth_tokens ADD COLUMN IF NOT EXISTS rotated BOOLEAN DEFAULT false;
+
+-- Create indexes for refresh lookups and family-wide revocation
+CREATE INDEX IF NOT EXISTS idx_tokens_refresh_token_hash ON auth_tokens(refresh_token_hash);
+CREATE INDEX IF NOT EXISTS idx_tokens_family_id ON auth_tokens(family_id);
@@ -0,0 +1,13 @@
+-- Create sync_events table: a per-user change feed used for offline catch-up.
+-- The id doubles as the monotonic cursor handed to clients.
+CREATE TABLE IF NOT EXISTS sync_events (
+    id BIGSERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    kind VARCHAR(50) NOT NULL, -- message_created, group_message_created, message_read, group_created
+    payload JSONB NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Create indexes for cursor scans
+CREATE INDEX IF NOT EXISTS idx_sync_events_user_cursor ON sync_events(user_id, id);
+CREATE INDEX IF NOT EXISTS idx_sync_events_created_at ON sync_events(created_at);
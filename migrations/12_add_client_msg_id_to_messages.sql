@@ -0,0 +1,9 @@
+-- Client-supplied idempotency keys so retried sends don't create duplicates
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS client_msg_id VARCHAR(64);
+ALTER TABLE group_messages ADD COLUMN IF NOT EXISTS client_msg_id VARCHAR(64);
+
+-- A client id is unique per sender
+CREATE UNIQUE INDEX IF NOT EXISTS idx_messages_sender_client_msg_id
+    ON messages(sender_id, client_msg_id) WHERE client_msg_id IS NOT NULL;
+CREATE UNIQUE INDEX IF NOT EXISTS idx_group_messages_sender_client_msg_id
+    ON group_messages(sender_id, client_msg_id) WHERE client_msg_id IS NOT NULL;
@@ -0,0 +1,14 @@
+-- Create message_edits table: previous versions of edited messages
+CREATE TABLE IF NOT EXISTS message_edits (
+    id SERIAL PRIMARY KEY,
+    message_id INTEGER REFERENCES messages(id) ON DELETE CASCADE,
+    group_message_id INTEGER REFERENCES group_messages(id) ON DELETE CASCADE,
+    editor_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    previous_content TEXT NOT NULL,
+    edited_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    CHECK ((message_id IS NULL) != (group_message_id IS NULL))
+);
+
+-- Create indexes for history lookups
+CREATE INDEX IF NOT EXISTS idx_message_edits_message ON message_edits(message_id, edited_at);
+CREATE INDEX IF NOT EXISTS idx_message_edits_group_message ON message_edits(group_message_id, edited_at);
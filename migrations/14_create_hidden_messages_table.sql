@@ -0,0 +1,15 @@
+-- Create hidden_messages table for "delete for me"
+CREATE TABLE IF NOT EXISTS hidden_messages (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    message_id INTEGER REFERENCES messages(id) ON DELETE CASCADE,
+    group_message_id INTEGER REFERENCES group_messages(id) ON DELETE CASCADE,
+    hidden_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE(user_id, message_id),
+    UNIQUE(user_id, group_message_id),
+    CHECK ((message_id IS NULL) != (group_message_id IS NULL))
+);
+
+-- Create indexes for history filtering
+CREATE INDEX IF NOT EXISTS idx_hidden_messages_message ON hidden_messages(message_id);
+CREATE INDEX IF NOT EXISTS idx_hidden_messages_group_message ON hidden_messages(group_message_id);
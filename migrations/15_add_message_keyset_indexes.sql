@@ -0,0 +1,3 @@
+-- Indexes for keyset (id-based) pagination of message history
+CREATE INDEX IF NOT EXISTS idx_messages_conversation_id_desc ON messages(conversation_id, id DESC);
+CREATE INDEX IF NOT EXISTS idx_group_messages_group_id_desc ON group_messages(group_id, id DESC);
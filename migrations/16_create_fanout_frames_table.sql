@@ -0,0 +1,8 @@
+-- Frames too large for a NOTIFY payload, shared between server nodes
+CREATE TABLE IF NOT EXISTS fanout_frames (
+    id BIGSERIAL PRIMARY KEY,
+    payload TEXT NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_fanout_frames_created_at ON fanout_frames(created_at);
@@ -0,0 +1,14 @@
+-- Create presence_connections table: every attached connection on every server node,
+-- so presence reflects a user's devices across nodes rather than on one
+CREATE TABLE IF NOT EXISTS presence_connections (
+    connection_id VARCHAR(36) PRIMARY KEY,
+    node_id VARCHAR(36) NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    -- Refreshed by the owning node; rows of a node that stopped refreshing are stale
+    seen_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Create indexes for per-user counts and per-node cleanup
+CREATE INDEX IF NOT EXISTS idx_presence_connections_user_id ON presence_connections(user_id);
+CREATE INDEX IF NOT EXISTS idx_presence_connections_node_id ON presence_connections(node_id);
+CREATE INDEX IF NOT EXISTS idx_presence_connections_seen_at ON presence_connections(seen_at);
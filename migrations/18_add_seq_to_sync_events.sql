@@ -0,0 +1,21 @@
+-- Number each user's sync events from a per-user counter. The counter row stays locked
+-- until the recording transaction commits, so a user's events become visible in seq
+-- order and a reader's cursor can never pass an event that commits later
+-- (a BIGSERIAL id is taken at insert time, not commit time).
+CREATE TABLE IF NOT EXISTS sync_sequences (
+    user_id INTEGER PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    last_seq BIGINT NOT NULL
+);
+
+ALTER TABLE sync_events ADD COLUMN IF NOT EXISTS seq BIGINT;
+
+-- Existing events keep their id as their seq, so cursors already handed out stay valid
+UPDATE sync_events SET seq = id WHERE seq IS NULL;
+INSERT INTO sync_sequences (user_id, last_seq)
+SELECT user_id, MAX(seq) FROM sync_events GROUP BY user_id
+ON CONFLICT (user_id) DO NOTHING;
+
+ALTER TABLE sync_events ALTER COLUMN seq SET NOT NULL;
+
+-- Create index for cursor scans
+CREATE UNIQUE INDEX IF NOT EXISTS idx_sync_events_user_seq ON sync_events(user_id, seq);
@@ -0,0 +1,5 @@
+-- Index the message id inside sync event payloads, so scrubbing a deleted message
+-- finds its events without scanning every user's feed
+CREATE INDEX IF NOT EXISTS idx_sync_events_message_id
+    ON sync_events (((payload->'message'->>'id')::INT))
+    WHERE payload ? 'message';
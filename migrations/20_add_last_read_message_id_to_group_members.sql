@@ -0,0 +1,10 @@
+-- Group read watermark by message id; last_read_at becomes the time it was last moved
+ALTER TABLE group_members ADD COLUMN IF NOT EXISTS last_read_message_id INTEGER;
+
+-- Carry over time-based watermarks: the newest message sent at or before them
+UPDATE group_members gm
+SET last_read_message_id = (
+    SELECT MAX(m.id) FROM group_messages m
+    WHERE m.group_id = gm.group_id AND m.sent_at <= gm.last_read_at
+)
+WHERE gm.last_read_message_id IS NULL AND gm.last_read_at IS NOT NULL;
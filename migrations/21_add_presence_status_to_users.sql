@@ -0,0 +1,3 @@
+-- Status chosen by the client (away / dnd) while connected; NULL means online.
+-- Shared by every node and cleared when the user's last device disconnects.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS presence_status VARCHAR(10);
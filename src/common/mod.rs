@@ -1,3 +1,5 @@
 pub mod response;
+pub mod pagination;
 
 pub use response::{ApiResponse, ErrorResponse};
+pub use pagination::{Cursor, Pagination};
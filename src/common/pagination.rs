@@ -0,0 +1,44 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use serde::{Deserialize, Serialize};
+
+/// Pagination metadata attached to list responses.
+/// Lists are newest first: `next_cursor` loads older items, `prev_cursor` newer ones.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Pagination {
+    pub next_cursor: Option<String>,
+    pub prev_cursor: Option<String>,
+    pub has_more: bool,
+}
+
+/// Keyset position in an id-ordered list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cursor {
+    /// Items with a smaller id (older)
+    Before(i32),
+    /// Items with a larger id (newer)
+    After(i32),
+}
+
+impl Cursor {
+    /// Encode as an opaque string for clients
+    pub fn encode(&self) -> String {
+        let raw = match self {
+            Cursor::Before(id) => format!("b:{}", id),
+            Cursor::After(id) => format!("a:{}", id),
+        };
+        URL_SAFE_NO_PAD.encode(raw)
+    }
+
+    /// Decode a string produced by `encode`
+    pub fn decode(value: &str) -> Option<Cursor> {
+        let bytes = URL_SAFE_NO_PAD.decode(value).ok()?;
+        let raw = String::from_utf8(bytes).ok()?;
+        let (direction, id) = raw.split_once(':')?;
+        let id = id.parse::<i32>().ok()?;
+        match direction {
+            "b" => Some(Cursor::Before(id)),
+            "a" => Some(Cursor::After(id)),
+            _ => None,
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use actix_web::{HttpResponse, http::StatusCode};
 use serde::{Deserialize, Serialize};
+use crate::common::pagination::Pagination;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ApiResponse<T> {
@@ -7,6 +8,8 @@ pub struct ApiResponse<T> {
     pub message: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub data: Option<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pagination: Option<Pagination>,
 }
 
 impl<T: Serialize> ApiResponse<T> {
@@ -15,15 +18,25 @@ impl<T: Serialize> ApiResponse<T> {
             success: true,
             message: message.to_string(),
             data: Some(data),
+            pagination: None,
+        })
+    }
+
+    pub fn paginated(message: &str, data: T, pagination: Pagination) -> HttpResponse {
+        HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            message: message.to_string(),
+            data: Some(data),
+            pagination: Some(pagination),
         })
     }
 
-    #[allow(dead_code)]
     pub fn success_no_data(message: &str) -> HttpResponse {
         HttpResponse::Ok().json(ApiResponse::<()> {
             success: true,
             message: message.to_string(),
             data: None,
+            pagination: None,
         })
     }
 }
@@ -52,6 +65,14 @@ impl ErrorResponse {
         })
     }
 
+    pub fn forbidden(message: &str) -> HttpResponse {
+        HttpResponse::Forbidden().json(ErrorResponse {
+            success: false,
+            message: message.to_string(),
+            error: "Forbidden".to_string(),
+        })
+    }
+
     pub fn not_found(message: &str) -> HttpResponse {
         HttpResponse::NotFound().json(ErrorResponse {
             success: false,
@@ -49,6 +49,19 @@ pub async fn run_migrations(pool: &DbPool) -> Result<(), Box<dyn std::error::Err
         include_str!("../../migrations/06_create_groups_table.sql"),
         include_str!("../../migrations/07_create_group_members_table.sql"),
         include_str!("../../migrations/08_create_group_messages_table.sql"),
+        include_str!("../../migrations/09_create_ws_tickets_table.sql"),
+        include_str!("../../migrations/10_add_refresh_rotation_to_auth_tokens.sql"),
+        include_str!("../../migrations/11_create_sync_events_table.sql"),
+        include_str!("../../migrations/12_add_client_msg_id_to_messages.sql"),
+        include_str!("../../migrations/13_create_message_edits_table.sql"),
+        include_str!("../../migrations/14_create_hidden_messages_table.sql"),
+        include_str!("../../migrations/15_add_message_keyset_indexes.sql"),
+        include_str!("../../migrations/16_create_fanout_frames_table.sql"),
+        include_str!("../../migrations/17_create_presence_connections_table.sql"),
+        include_str!("../../migrations/18_add_seq_to_sync_events.sql"),
+        include_str!("../../migrations/19_add_sync_events_message_index.sql"),
+        include_str!("../../migrations/20_add_last_read_message_id_to_group_members.sql"),
+        include_str!("../../migrations/21_add_presence_status_to_users.sql"),
     ];
 
     for (index, migration) in migrations.iter().enumerate() {
@@ -8,6 +8,7 @@ use actix_web::{web, App, HttpServer, middleware};
 use actix_cors::Cors;
 use dotenv::dotenv;
 use std::env;
+use std::time::Duration;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -49,14 +50,36 @@ async fn main() -> std::io::Result<()> {
     // Create pool data
     let pool_data = web::Data::new(pool);
 
-    // Initialize Chat Server (Hub)
-    let chat_server = modules::ws::ChatServer::new();
+    // Initialize Chat Server (Hub); with WS_FANOUT=postgres, nodes share frames via LISTEN/NOTIFY
+    let chat_server = match env::var("WS_FANOUT").as_deref() {
+        Ok("postgres") => {
+            log::info!("Using Postgres LISTEN/NOTIFY fanout");
+            let fanout = modules::ws::fanout::PostgresFanout::new(pool_data.get_ref().clone());
+            modules::ws::ChatServer::with_fanout(std::sync::Arc::new(fanout))
+        }
+        _ => modules::ws::ChatServer::new(),
+    };
     let chat_server_data = web::Data::new(chat_server);
+    let presence_data = web::Data::new(modules::presence::PresenceService::new());
+    actix_rt::spawn(
+        presence_data
+            .get_ref()
+            .clone()
+            .run_heartbeat(pool_data.get_ref().clone(), chat_server_data.get_ref().clone()),
+    );
+    actix_rt::spawn(modules::sync::SyncService::run_pruning(
+        pool_data.get_ref().clone(),
+        chat_server_data.get_ref().clone(),
+    ));
 
     log::info!("Server starting at http://{}:{}", host, port);
 
     // Start HTTP server
-    HttpServer::new(move || {
+    let shutdown_chat_server = chat_server_data.clone();
+    let shutdown_presence = presence_data.clone();
+    let shutdown_pool = pool_data.clone();
+
+    let server = HttpServer::new(move || {
         let cors = Cors::default()
             .allow_any_origin()
             .allow_any_method()
@@ -66,7 +89,11 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .app_data(pool_data.clone())
             .app_data(chat_server_data.clone())
-            .wrap(middleware::Logger::default())
+            .app_data(presence_data.clone())
+            .wrap(
+                middleware::Logger::new(r#"%a "%{request_line}xi" %s %b "%{Referer}i" "%{User-Agent}i" %T"#)
+                    .custom_request_replace("request_line", modules::ws::sse::logged_request_line),
+            )
             .wrap(cors)
             .wrap(modules::auth::AuthMiddleware)
             .service(
@@ -75,11 +102,53 @@ async fn main() -> std::io::Result<()> {
                     .configure(modules::configure_users)
                     .configure(modules::configure_contacts)
                     .configure(modules::configure_chats)
+                    .configure(modules::configure_sync)
+                    .configure(modules::configure_presence)
+                    .configure(modules::configure_events)
             )
             .configure(modules::configure_ws)
             .route("/health", web::get().to(|| async { "OK" }))
     })
+    // Signals are handled below so WebSocket clients can be drained first
+    .disable_signals()
     .bind((host.as_str(), port))?
-    .run()
-    .await
+    .run();
+
+    let server_handle = server.handle();
+    actix_rt::spawn(async move {
+        shutdown_signal().await;
+        log::info!("Shutdown signal received, draining connections...");
+
+        let grace = Duration::from_secs(env_u64("SHUTDOWN_GRACE_PERIOD", 10));
+        let spread = Duration::from_secs(env_u64("SHUTDOWN_RECONNECT_SPREAD", 5));
+
+        shutdown_presence.shutdown(&shutdown_pool, &shutdown_chat_server, grace).await;
+        shutdown_chat_server.shutdown(grace, spread).await;
+        server_handle.stop(true).await;
+    });
+
+    server.await
+}
+
+/// Resolve on SIGINT (Ctrl+C) or SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+fn env_u64(key: &str, default: u64) -> u64 {
+    env::var(key)
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(default)
 }
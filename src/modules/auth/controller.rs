@@ -3,7 +3,7 @@ use validator::Validate;
 
 use crate::db::DbPool;
 use crate::common::{ApiResponse, ErrorResponse};
-use crate::modules::auth::model::{RegisterInput, LoginInput};
+use crate::modules::auth::model::{RegisterInput, LoginInput, RefreshInput};
 use crate::modules::auth::services::AuthService;
 
 /// POST /api/auth/register - Register new user
@@ -98,6 +98,47 @@ pub async fn logout(
     ErrorResponse::unauthorized("No token provided")
 }
 
+/// POST /api/auth/refresh - Exchange a refresh token for a new token pair
+pub async fn refresh(
+    pool: web::Data<DbPool>,
+    input: web::Json<RefreshInput>,
+) -> HttpResponse {
+    if let Err(errors) = input.validate() {
+        return ErrorResponse::bad_request(&format!("Validation error: {:?}", errors));
+    }
+
+    match AuthService::refresh(&pool, &input.refresh_token).await {
+        Ok(tokens) => ApiResponse::success("Token refreshed", tokens),
+        Err(e) => {
+            log::error!("Refresh error: {}", e);
+            if e.to_string().contains("Invalid") || e.to_string().contains("deactivated") {
+                ErrorResponse::unauthorized(&e.to_string())
+            } else {
+                ErrorResponse::internal_error("Failed to refresh token")
+            }
+        }
+    }
+}
+
+/// POST /api/auth/ws-ticket - Issue a short-lived ticket for the WebSocket handshake
+pub async fn ws_ticket(
+    pool: web::Data<DbPool>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let user_id = match req.extensions().get::<i32>().copied() {
+        Some(id) => id,
+        None => return ErrorResponse::unauthorized("Unauthorized"),
+    };
+
+    match AuthService::issue_ws_ticket(&pool, user_id).await {
+        Ok(ticket) => ApiResponse::success("Ticket issued", ticket),
+        Err(e) => {
+            log::error!("WS ticket error: {}", e);
+            ErrorResponse::internal_error("Failed to issue ticket")
+        }
+    }
+}
+
 /// Configure auth routes
 pub fn configure(cfg: &mut web::ServiceConfig) {
     cfg.service(
@@ -105,6 +146,8 @@ pub fn configure(cfg: &mut web::ServiceConfig) {
             .route("/register", web::post().to(register))
             .route("/login", web::post().to(login))
             .route("/logout", web::post().to(logout))
-            .route("/me", web::get().to(get_current_user)),
+            .route("/refresh", web::post().to(refresh))
+            .route("/me", web::get().to(get_current_user))
+            .route("/ws-ticket", web::post().to(ws_ticket)),
     );
 }
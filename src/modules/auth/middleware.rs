@@ -1,10 +1,12 @@
 use std::future::{ready, Ready};
+use std::rc::Rc;
 use actix_web::{
     dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    Error, HttpMessage,
+    web, Error, HttpMessage,
 };
 use futures_util::future::LocalBoxFuture;
-use crate::utils::verify_jwt;
+use crate::db::DbPool;
+use crate::modules::auth::services::AuthService;
 
 // There are two steps in middleware processing.
 // 1. Middleware initialization, middleware factory gets called with
@@ -26,12 +28,13 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ready(Ok(AuthMiddlewareService { service }))
+        ready(Ok(AuthMiddlewareService { service: Rc::new(service) }))
     }
 }
 
 pub struct AuthMiddlewareService<S> {
-    service: S,
+    // Rc so the inner service can be called after the async token lookup
+    service: Rc<S>,
 }
 
 impl<S, B> Service<ServiceRequest> for AuthMiddlewareService<S>
@@ -47,29 +50,30 @@ where
     forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        // 1. Check for Authorization header
-        if let Some(auth_header) = req.headers().get("Authorization") {
-            if let Ok(auth_str) = auth_header.to_str() {
-                if auth_str.starts_with("Bearer ") {
-                    let token = &auth_str[7..];
-                    
-                    // 2. Verify Token
-                    if let Ok(claims) = verify_jwt(token) {
+        let service = Rc::clone(&self.service);
+
+        Box::pin(async move {
+            // 1. Check for Authorization header
+            let token = req
+                .headers()
+                .get("Authorization")
+                .and_then(|h| h.to_str().ok())
+                .and_then(|s| s.strip_prefix("Bearer "))
+                .map(str::to_string);
+
+            if let (Some(token), Some(pool)) = (token, req.app_data::<web::Data<DbPool>>().cloned()) {
+                // 2. Verify Token (signature, expiry and revocation)
+                match AuthService::authenticate(&pool, &token).await {
+                    Ok(claims) => {
                         // 3. Set user_id in extensions
                         req.extensions_mut().insert(claims.sub);
                         log::debug!("AuthMiddleware: Valid token for user_id {}", claims.sub);
-                    } else {
-                        log::warn!("AuthMiddleware: Invalid token provided");
                     }
+                    Err(e) => log::warn!("AuthMiddleware: Rejected token: {}", e),
                 }
             }
-        }
 
-        let fut = self.service.call(req);
-
-        Box::pin(async move {
-            let res = fut.await?;
-            Ok(res)
+            service.call(req).await
         })
     }
 }
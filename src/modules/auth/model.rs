@@ -82,7 +82,28 @@ pub struct AuthToken {
     pub token_hash: String,
     pub refresh_token_hash: Option<String>,
     pub expires_at: DateTime<Utc>,
+    pub refresh_expires_at: Option<DateTime<Utc>>,
+    pub family_id: Option<String>,
+    pub rotated: bool,
     pub created_at: DateTime<Utc>,
     pub revoked: bool,
     pub device_info: Option<String>,
 }
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct RefreshInput {
+    #[validate(length(min = 1))]
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenResponse {
+    pub token: String,
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WsTicketResponse {
+    pub ticket: String,
+    pub expires_in: i64, // seconds
+}
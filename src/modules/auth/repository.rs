@@ -1,6 +1,7 @@
 use crate::db::DbPool;
 use crate::modules::auth::model::{User, AuthToken};
 use chrono::{DateTime, Utc};
+use tokio_postgres::Row;
 
 pub struct AuthRepository;
 
@@ -111,35 +112,32 @@ impl AuthRepository {
     }
 
     /// Store auth token
+    #[allow(clippy::too_many_arguments)]
     pub async fn store_token(
         pool: &DbPool,
         user_id: i32,
         token_hash: &str,
         refresh_token_hash: &str,
         expires_at: DateTime<Utc>,
+        refresh_expires_at: DateTime<Utc>,
+        family_id: &str,
         device_info: Option<&str>,
     ) -> Result<AuthToken, Box<dyn std::error::Error>> {
         let client = pool.get().await?;
 
         let row = client
             .query_one(
-                "INSERT INTO auth_tokens (user_id, token_hash, refresh_token_hash, expires_at, device_info) 
-                 VALUES ($1, $2, $3, $4, $5) 
-                 RETURNING id, user_id, token_hash, refresh_token_hash, expires_at, created_at, revoked, device_info",
-                &[&user_id, &token_hash, &refresh_token_hash, &expires_at, &device_info],
+                &format!(
+                    "INSERT INTO auth_tokens (user_id, token_hash, refresh_token_hash, expires_at, refresh_expires_at, family_id, device_info) 
+                     VALUES ($1, $2, $3, $4, $5, $6, $7) 
+                     RETURNING {}",
+                    TOKEN_COLUMNS
+                ),
+                &[&user_id, &token_hash, &refresh_token_hash, &expires_at, &refresh_expires_at, &family_id, &device_info],
             )
             .await?;
 
-        Ok(AuthToken {
-            id: row.get(0),
-            user_id: row.get(1),
-            token_hash: row.get(2),
-            refresh_token_hash: row.get(3),
-            expires_at: row.get(4),
-            created_at: row.get(5),
-            revoked: row.get(6),
-            device_info: row.get(7),
-        })
+        Ok(token_from_row(&row))
     }
 
     /// Update last seen timestamp
@@ -174,4 +172,136 @@ impl AuthRepository {
 
         Ok(())
     }
+
+    /// Find a token that is neither revoked nor expired
+    pub async fn find_active_token(
+        pool: &DbPool,
+        token_hash: &str,
+    ) -> Result<Option<AuthToken>, Box<dyn std::error::Error>> {
+        let client = pool.get().await?;
+
+        let result = client
+            .query_opt(
+                &format!(
+                    "SELECT {} FROM auth_tokens
+                     WHERE token_hash = $1 AND revoked = false AND expires_at > CURRENT_TIMESTAMP",
+                    TOKEN_COLUMNS
+                ),
+                &[&token_hash],
+            )
+            .await?;
+
+        Ok(result.as_ref().map(token_from_row))
+    }
+
+    /// Find a token row by its refresh token hash, whatever its state
+    pub async fn find_by_refresh_hash(
+        pool: &DbPool,
+        refresh_token_hash: &str,
+    ) -> Result<Option<AuthToken>, Box<dyn std::error::Error>> {
+        let client = pool.get().await?;
+
+        let result = client
+            .query_opt(
+                &format!("SELECT {} FROM auth_tokens WHERE refresh_token_hash = $1", TOKEN_COLUMNS),
+                &[&refresh_token_hash],
+            )
+            .await?;
+
+        Ok(result.as_ref().map(token_from_row))
+    }
+
+    /// Mark a token row as rotated (and revoked).
+    /// Returns false if another request already rotated it.
+    pub async fn mark_rotated(
+        pool: &DbPool,
+        token_id: i32,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let client = pool.get().await?;
+
+        let updated = client
+            .execute(
+                "UPDATE auth_tokens SET rotated = true, revoked = true
+                 WHERE id = $1 AND rotated = false AND revoked = false",
+                &[&token_id],
+            )
+            .await?;
+
+        Ok(updated == 1)
+    }
+
+    /// Revoke every token descended from the same login
+    pub async fn revoke_family(
+        pool: &DbPool,
+        family_id: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let client = pool.get().await?;
+
+        client
+            .execute(
+                "UPDATE auth_tokens SET revoked = true WHERE family_id = $1",
+                &[&family_id],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Store a WebSocket handshake ticket
+    pub async fn store_ws_ticket(
+        pool: &DbPool,
+        user_id: i32,
+        ticket_hash: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let client = pool.get().await?;
+
+        client
+            .execute(
+                "INSERT INTO ws_tickets (user_id, ticket_hash, expires_at) VALUES ($1, $2, $3)",
+                &[&user_id, &ticket_hash, &expires_at],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Consume a WebSocket ticket (single use) and return its user_id
+    pub async fn consume_ws_ticket(
+        pool: &DbPool,
+        ticket_hash: &str,
+    ) -> Result<Option<i32>, Box<dyn std::error::Error>> {
+        let client = pool.get().await?;
+
+        // Marking it used in the same statement makes redemption atomic
+        let row = client
+            .query_opt(
+                "UPDATE ws_tickets SET used = true
+                 WHERE ticket_hash = $1 AND used = false AND expires_at > CURRENT_TIMESTAMP
+                 RETURNING user_id",
+                &[&ticket_hash],
+            )
+            .await?;
+
+        Ok(row.map(|r| r.get(0)))
+    }
+}
+
+const TOKEN_COLUMNS: &str = "id, user_id, token_hash, refresh_token_hash, expires_at, refresh_expires_at, \
+                             family_id, rotated, created_at, revoked, device_info";
+
+fn token_from_row(row: &Row) -> AuthToken {
+    AuthToken {
+        id: row.get(0),
+        user_id: row.get(1),
+        token_hash: row.get(2),
+        refresh_token_hash: row.get(3),
+        expires_at: row.get(4),
+        refresh_expires_at: row.get(5),
+        family_id: row.get(6),
+        rotated: row.get(7),
+        created_at: row.get(8),
+        revoked: row.get(9),
+        device_info: row.get(10),
+    }
 }
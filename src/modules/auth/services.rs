@@ -1,8 +1,14 @@
 use crate::db::DbPool;
-use crate::modules::auth::model::{User, RegisterInput, LoginInput, AuthResponse, UserPublic};
+use crate::modules::auth::model::{
+    User, RegisterInput, LoginInput, AuthResponse, UserPublic, TokenResponse, WsTicketResponse,
+};
 use crate::modules::auth::repository::AuthRepository;
-use crate::utils::{hash_password, verify_password, generate_jwt, generate_refresh_token};
+use crate::utils::{
+    hash_password, verify_password, generate_jwt, verify_jwt, generate_refresh_token, hash_token, Claims,
+    jwt_expiration, refresh_token_expiration,
+};
 use chrono::{Duration, Utc};
+use std::env;
 
 pub struct AuthService;
 
@@ -32,24 +38,10 @@ impl AuthService {
         )
         .await?;
 
-        // Generate tokens
-        let token = generate_jwt(user.id, &user.email)?;
-        let refresh_token = generate_refresh_token();
-
-        // Store tokens
-        let token_hash = hash_password(&token)?;
-        let refresh_token_hash = hash_password(&refresh_token)?;
-        let expires_at = Utc::now() + Duration::seconds(3600);
-
-        AuthRepository::store_token(
-            pool,
-            user.id,
-            &token_hash,
-            &refresh_token_hash,
-            expires_at,
-            None,
-        )
-        .await?;
+        // Generate and store tokens (each login starts a new refresh-token family)
+        let family_id = uuid::Uuid::new_v4().to_string();
+        let TokenResponse { token, refresh_token } =
+            Self::issue_tokens(pool, user.id, &user.email, &family_id).await?;
 
         Ok(AuthResponse {
             user: UserPublic::from(user),
@@ -78,24 +70,10 @@ impl AuthService {
             return Err("Account is deactivated".into());
         }
 
-        // Generate tokens
-        let token = generate_jwt(user.id, &user.email)?;
-        let refresh_token = generate_refresh_token();
-
-        // Store tokens
-        let token_hash = hash_password(&token)?;
-        let refresh_token_hash = hash_password(&refresh_token)?;
-        let expires_at = Utc::now() + Duration::seconds(3600);
-
-        AuthRepository::store_token(
-            pool,
-            user.id,
-            &token_hash,
-            &refresh_token_hash,
-            expires_at,
-            None,
-        )
-        .await?;
+        // Generate and store tokens (each login starts a new refresh-token family)
+        let family_id = uuid::Uuid::new_v4().to_string();
+        let TokenResponse { token, refresh_token } =
+            Self::issue_tokens(pool, user.id, &user.email, &family_id).await?;
 
         // Update last seen
         AuthRepository::update_last_seen(pool, user.id).await?;
@@ -120,8 +98,121 @@ impl AuthService {
         token: &str,
     ) -> Result<(), Box<dyn std::error::Error>> {
         // We hash the token to find it in the DB, as we store hashed tokens
-        let token_hash = hash_password(token)?;
+        let token_hash = hash_token(token);
         AuthRepository::revoke_token(pool, &token_hash).await?;
         Ok(())
     }
+
+    /// Exchange a refresh token for a new access/refresh pair.
+    /// The old pair is revoked; presenting an already-rotated refresh token
+    /// is treated as theft and revokes every token in its family.
+    pub async fn refresh(
+        pool: &DbPool,
+        refresh_token: &str,
+    ) -> Result<TokenResponse, Box<dyn std::error::Error>> {
+        let stored = AuthRepository::find_by_refresh_hash(pool, &hash_token(refresh_token))
+            .await?
+            .ok_or("Invalid refresh token")?;
+
+        // Rows created before rotation tracking have no family; treat the row as its own family
+        let family_id = stored.family_id.clone().unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+        if stored.rotated {
+            log::warn!("Refresh token reuse detected for user {}", stored.user_id);
+            AuthRepository::revoke_family(pool, &family_id).await?;
+            return Err("Invalid refresh token: reuse detected".into());
+        }
+
+        let refresh_expired = stored.refresh_expires_at.is_none_or(|exp| exp <= Utc::now());
+        if stored.revoked || refresh_expired {
+            return Err("Invalid refresh token".into());
+        }
+
+        // Only one concurrent refresh may win the rotation
+        if !AuthRepository::mark_rotated(pool, stored.id).await? {
+            AuthRepository::revoke_family(pool, &family_id).await?;
+            return Err("Invalid refresh token: reuse detected".into());
+        }
+
+        let user = AuthRepository::find_by_id(pool, stored.user_id)
+            .await?
+            .ok_or("Invalid refresh token")?;
+
+        if !user.is_active {
+            return Err("Account is deactivated".into());
+        }
+
+        Self::issue_tokens(pool, user.id, &user.email, &family_id).await
+    }
+
+    /// Generate an access/refresh pair and persist their hashes
+    async fn issue_tokens(
+        pool: &DbPool,
+        user_id: i32,
+        email: &str,
+        family_id: &str,
+    ) -> Result<TokenResponse, Box<dyn std::error::Error>> {
+        let token = generate_jwt(user_id, email)?;
+        let refresh_token = generate_refresh_token();
+
+        let now = Utc::now();
+        AuthRepository::store_token(
+            pool,
+            user_id,
+            &hash_token(&token),
+            &hash_token(&refresh_token),
+            now + Duration::seconds(jwt_expiration()),
+            now + Duration::seconds(refresh_token_expiration()),
+            family_id,
+            None,
+        )
+        .await?;
+
+        Ok(TokenResponse { token, refresh_token })
+    }
+
+    /// Verify a JWT and make sure it has not been revoked
+    pub async fn authenticate(
+        pool: &DbPool,
+        token: &str,
+    ) -> Result<Claims, Box<dyn std::error::Error>> {
+        let claims = verify_jwt(token).map_err(|_| "Invalid token")?;
+
+        if AuthRepository::find_active_token(pool, &hash_token(token)).await?.is_none() {
+            return Err("Token has been revoked".into());
+        }
+
+        Ok(claims)
+    }
+
+    /// Issue a short-lived, single-use ticket for the WebSocket handshake
+    pub async fn issue_ws_ticket(
+        pool: &DbPool,
+        user_id: i32,
+    ) -> Result<WsTicketResponse, Box<dyn std::error::Error>> {
+        let ttl = env::var("WS_TICKET_TTL")
+            .unwrap_or_else(|_| "30".to_string())
+            .parse::<i64>()
+            .unwrap_or(30);
+
+        let ticket = generate_refresh_token();
+        let expires_at = Utc::now() + Duration::seconds(ttl);
+
+        AuthRepository::store_ws_ticket(pool, user_id, &hash_token(&ticket), expires_at).await?;
+
+        Ok(WsTicketResponse {
+            ticket,
+            expires_in: ttl,
+        })
+    }
+
+    /// Redeem a WebSocket ticket, returning the user it was issued to
+    pub async fn redeem_ws_ticket(
+        pool: &DbPool,
+        ticket: &str,
+    ) -> Result<i32, Box<dyn std::error::Error>> {
+        AuthRepository::consume_ws_ticket(pool, &hash_token(ticket))
+            .await?
+            .ok_or_else(|| "Invalid or expired ticket".into())
+    }
 }
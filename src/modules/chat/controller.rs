@@ -1,25 +1,70 @@
 use actix_web::{web, HttpResponse, HttpRequest, HttpMessage};
 use crate::db::DbPool;
-use crate::common::{ApiResponse, ErrorResponse};
+use crate::common::{ApiResponse, Cursor, ErrorResponse, Pagination};
+use crate::modules::chat::model::{
+    DeleteMessageQuery, EditMessageInput, HistoryPage, PageAnchor, ReadUpToInput, SendMessageInput,
+    TypingInput,
+};
 use crate::modules::chat::repository::MessageRepository;
+use crate::modules::chat::services::ChatService;
+use crate::modules::ws::ChatServer;
+use validator::Validate;
 
-// Helper to extract user_id (same hack as contacts module, in real app usage middleware)
+/// The authenticated user, as set by `AuthMiddleware` from a valid bearer token
 fn extract_user_id(req: &HttpRequest) -> Option<i32> {
-    req.extensions().get::<i32>().copied().or_else(|| {
-        req.headers().get("X-User-Id")
-           .and_then(|h| h.to_str().ok())
-           .and_then(|s| s.parse::<i32>().ok())
-           .filter(|&id| id > 0)
-    })
+    req.extensions().get::<i32>().copied()
 }
 
-/// GET /api/chats/{partner_id}/messages?limit=20&offset=0
+/// Query for history endpoints. At most one anchor is used, in order of precedence:
+/// `cursor` (from a previous response), `around_id`, `after_id`, `before_id`.
 #[derive(serde::Deserialize)]
 pub struct HistoryQuery {
     pub limit: Option<i64>,
-    pub offset: Option<i64>,
+    pub cursor: Option<String>,
+    pub before_id: Option<i32>,
+    pub after_id: Option<i32>,
+    pub around_id: Option<i32>,
 }
 
+impl HistoryQuery {
+    fn anchor(&self) -> Option<PageAnchor> {
+        if let Some(cursor) = &self.cursor {
+            return match Cursor::decode(cursor)? {
+                Cursor::Before(id) => Some(PageAnchor::Before(id)),
+                Cursor::After(id) => Some(PageAnchor::After(id)),
+            };
+        }
+
+        Some(match (self.around_id, self.after_id, self.before_id) {
+            (Some(id), _, _) => PageAnchor::Around(id),
+            (None, Some(id), _) => PageAnchor::After(id),
+            (None, None, Some(id)) => PageAnchor::Before(id),
+            (None, None, None) => PageAnchor::Latest,
+        })
+    }
+
+    fn limit(&self) -> i64 {
+        self.limit.unwrap_or(50).clamp(1, 100) // Max 100
+    }
+}
+
+/// Build cursors from the ids at either end of a newest-first page
+fn pagination<T>(page: &HistoryPage<T>, id_of: impl Fn(&T) -> i32) -> Pagination {
+    let next_cursor = page.items.last()
+        .filter(|_| page.has_older)
+        .map(|item| Cursor::Before(id_of(item)).encode());
+    let prev_cursor = page.items.first()
+        .filter(|_| page.has_newer)
+        .map(|item| Cursor::After(id_of(item)).encode());
+
+    Pagination {
+        has_more: next_cursor.is_some(),
+        next_cursor,
+        prev_cursor,
+    }
+}
+
+/// GET /api/chats/{partner_id}/messages?limit=50&before_id=&after_id=&around_id=&cursor=
 pub async fn get_chat_history(
     pool: web::Data<DbPool>,
     req: HttpRequest,
@@ -32,11 +77,16 @@ pub async fn get_chat_history(
     };
     
     let partner_id = path.into_inner();
-    let limit = query.limit.unwrap_or(50).min(100); // Max 100
-    let offset = query.offset.unwrap_or(0);
+    let anchor = match query.anchor() {
+        Some(anchor) => anchor,
+        None => return ErrorResponse::bad_request("Invalid cursor"),
+    };
 
-    match MessageRepository::get_messages(&pool, user_id, partner_id, limit, offset).await {
-        Ok(messages) => ApiResponse::success("Messages retrieved", messages),
+    match MessageRepository::get_messages(&pool, user_id, partner_id, anchor, query.limit()).await {
+        Ok(page) => {
+            let pagination = pagination(&page, |m| m.id);
+            ApiResponse::paginated("Messages retrieved", page.items, pagination)
+        }
         Err(e) => {
             log::error!("Get messages error: {}", e);
             ErrorResponse::internal_error("Failed to retrieve messages")
@@ -44,9 +94,283 @@ pub async fn get_chat_history(
     }
 }
 
+/// GET /api/chats/groups/{group_id}/messages?limit=50&before_id=&after_id=&around_id=&cursor=
+pub async fn get_group_history(
+    pool: web::Data<DbPool>,
+    req: HttpRequest,
+    path: web::Path<i32>,
+    query: web::Query<HistoryQuery>,
+) -> HttpResponse {
+    let user_id = match extract_user_id(&req) {
+        Some(id) => id,
+        None => return ErrorResponse::unauthorized("Unauthorized"),
+    };
+
+    let group_id = path.into_inner();
+    let anchor = match query.anchor() {
+        Some(anchor) => anchor,
+        None => return ErrorResponse::bad_request("Invalid cursor"),
+    };
+
+    match MessageRepository::get_group_messages(&pool, group_id, user_id, anchor, query.limit()).await {
+        Ok(page) => {
+            let pagination = pagination(&page, |m| m.message.id);
+            ApiResponse::paginated("Messages retrieved", page.items, pagination)
+        }
+        Err(e) => {
+            if e.to_string().contains("not a member") {
+                ErrorResponse::forbidden(&e.to_string())
+            } else {
+                log::error!("Get group messages error: {}", e);
+                ErrorResponse::internal_error("Failed to retrieve messages")
+            }
+        }
+    }
+}
+
+/// Map send errors to responses
+fn send_error(e: Box<dyn std::error::Error>) -> HttpResponse {
+    let msg = e.to_string();
+    if msg.contains("limited to") {
+        ErrorResponse::custom(actix_web::http::StatusCode::PAYLOAD_TOO_LARGE, &msg, "Payload Too Large")
+    } else if msg.contains("already used") {
+        ErrorResponse::custom(actix_web::http::StatusCode::CONFLICT, &msg, "Conflict")
+    } else if msg.contains("empty") || msg.contains("yourself") || msg.contains("client_msg_id") {
+        ErrorResponse::bad_request(&msg)
+    } else if msg.contains("not a member") {
+        ErrorResponse::forbidden(&msg)
+    } else if msg.contains("not found") {
+        ErrorResponse::not_found(&msg)
+    } else {
+        log::error!("Send message error: {}", e);
+        ErrorResponse::internal_error("Failed to send message")
+    }
+}
+
+/// POST /api/chats/{partner_id}/messages
+/// Same as a `TextMessage` frame: the recipient gets it live over `/ws` or `/api/events`
+pub async fn send_message(
+    pool: web::Data<DbPool>,
+    srv: web::Data<ChatServer>,
+    req: HttpRequest,
+    path: web::Path<i32>,
+    input: web::Json<SendMessageInput>,
+) -> HttpResponse {
+    let user_id = match extract_user_id(&req) {
+        Some(id) => id,
+        None => return ErrorResponse::unauthorized("Unauthorized"),
+    };
+
+    let input = input.into_inner();
+    match ChatService::send_direct(&pool, &srv, user_id, path.into_inner(), &input.content, input.client_msg_id, None).await {
+        Ok(message) => ApiResponse::success("Message sent", message),
+        Err(e) => send_error(e),
+    }
+}
+
+/// POST /api/chats/groups/{group_id}/messages
+/// Same as a `GroupMessage` frame
+pub async fn send_group_message(
+    pool: web::Data<DbPool>,
+    srv: web::Data<ChatServer>,
+    req: HttpRequest,
+    path: web::Path<i32>,
+    input: web::Json<SendMessageInput>,
+) -> HttpResponse {
+    let user_id = match extract_user_id(&req) {
+        Some(id) => id,
+        None => return ErrorResponse::unauthorized("Unauthorized"),
+    };
+
+    let input = input.into_inner();
+    match ChatService::send_group(&pool, &srv, user_id, path.into_inner(), &input.content, input.client_msg_id, None).await {
+        Ok(message) => ApiResponse::success("Message sent", message),
+        Err(e) => send_error(e),
+    }
+}
+
+/// PATCH /api/chats/messages/{id}
+pub async fn edit_message(
+    pool: web::Data<DbPool>,
+    srv: web::Data<ChatServer>,
+    req: HttpRequest,
+    path: web::Path<i32>,
+    input: web::Json<EditMessageInput>,
+) -> HttpResponse {
+    let user_id = match extract_user_id(&req) {
+        Some(id) => id,
+        None => return ErrorResponse::unauthorized("Unauthorized"),
+    };
+
+    if let Err(errors) = input.validate() {
+        return ErrorResponse::bad_request(&format!("Validation error: {:?}", errors));
+    }
+
+    let message_id = path.into_inner();
+
+    match ChatService::edit_message(&pool, &srv, user_id, message_id, input.group_id, &input.content).await {
+        Ok(message) => ApiResponse::success("Message edited", message),
+        Err(e) => {
+            let msg = e.to_string();
+            if msg.contains("limited to") {
+                ErrorResponse::custom(actix_web::http::StatusCode::PAYLOAD_TOO_LARGE, &msg, "Payload Too Large")
+            } else if msg.contains("empty") {
+                ErrorResponse::bad_request(&msg)
+            } else if msg.contains("not found") {
+                ErrorResponse::not_found(&msg)
+            } else if msg.contains("Only the sender") || msg.contains("expired") {
+                ErrorResponse::forbidden(&msg)
+            } else {
+                log::error!("Edit message error: {}", e);
+                ErrorResponse::internal_error("Failed to edit message")
+            }
+        }
+    }
+}
+
+/// DELETE /api/chats/messages/{id}?scope=me|everyone&group_id=
+pub async fn delete_message(
+    pool: web::Data<DbPool>,
+    srv: web::Data<ChatServer>,
+    req: HttpRequest,
+    path: web::Path<i32>,
+    query: web::Query<DeleteMessageQuery>,
+) -> HttpResponse {
+    let user_id = match extract_user_id(&req) {
+        Some(id) => id,
+        None => return ErrorResponse::unauthorized("Unauthorized"),
+    };
+
+    let for_everyone = match query.scope.as_deref().unwrap_or("me") {
+        "me" => false,
+        "everyone" => true,
+        _ => return ErrorResponse::bad_request("scope must be 'me' or 'everyone'"),
+    };
+
+    let message_id = path.into_inner();
+
+    match ChatService::delete_message(&pool, &srv, user_id, message_id, query.group_id, for_everyone).await {
+        Ok(()) => ApiResponse::<()>::success_no_data("Message deleted"),
+        Err(e) => {
+            let msg = e.to_string();
+            if msg.contains("not found") {
+                ErrorResponse::not_found(&msg)
+            } else if msg.contains("Only the sender") {
+                ErrorResponse::forbidden(&msg)
+            } else {
+                log::error!("Delete message error: {}", e);
+                ErrorResponse::internal_error("Failed to delete message")
+            }
+        }
+    }
+}
+
+/// POST /api/chats/typing
+/// Typing indicator for clients without a WebSocket (e.g. on `/api/events`)
+pub async fn send_typing(
+    pool: web::Data<DbPool>,
+    srv: web::Data<ChatServer>,
+    req: HttpRequest,
+    input: web::Json<TypingInput>,
+) -> HttpResponse {
+    let user_id = match extract_user_id(&req) {
+        Some(id) => id,
+        None => return ErrorResponse::unauthorized("Unauthorized"),
+    };
+
+    match ChatService::typing(&pool, &srv, user_id, input.conversation_id, input.group_id, input.is_typing, None).await {
+        Ok(()) => ApiResponse::<()>::success_no_data("Typing status sent"),
+        Err(e) => {
+            let msg = e.to_string();
+            if msg.contains("not a member") {
+                ErrorResponse::forbidden(&msg)
+            } else if msg.contains("not found") {
+                ErrorResponse::not_found(&msg)
+            } else if msg.contains("required") {
+                ErrorResponse::bad_request(&msg)
+            } else {
+                log::error!("Typing error: {}", e);
+                ErrorResponse::internal_error("Failed to send typing status")
+            }
+        }
+    }
+}
+
+/// Map read receipt errors to responses
+fn read_error(e: Box<dyn std::error::Error>) -> HttpResponse {
+    let msg = e.to_string();
+    if msg.contains("not a member") {
+        ErrorResponse::forbidden(&msg)
+    } else if msg.contains("not found") {
+        ErrorResponse::not_found(&msg)
+    } else if msg.contains("your own message") {
+        ErrorResponse::bad_request(&msg)
+    } else {
+        log::error!("Mark read error: {}", e);
+        ErrorResponse::internal_error("Failed to mark as read")
+    }
+}
+
+/// POST /api/chats/messages/{id}/read
+pub async fn mark_message_read(
+    pool: web::Data<DbPool>,
+    srv: web::Data<ChatServer>,
+    req: HttpRequest,
+    path: web::Path<i32>,
+) -> HttpResponse {
+    let user_id = match extract_user_id(&req) {
+        Some(id) => id,
+        None => return ErrorResponse::unauthorized("Unauthorized"),
+    };
+
+    match ChatService::mark_message_read(&pool, &srv, user_id, path.into_inner()).await {
+        Ok(()) => ApiResponse::<()>::success_no_data("Message marked as read"),
+        Err(e) => read_error(e),
+    }
+}
+
+/// POST /api/chats/conversations/{conversation_id}/read
+pub async fn mark_conversation_read(
+    pool: web::Data<DbPool>,
+    srv: web::Data<ChatServer>,
+    req: HttpRequest,
+    path: web::Path<i32>,
+    input: web::Json<ReadUpToInput>,
+) -> HttpResponse {
+    let user_id = match extract_user_id(&req) {
+        Some(id) => id,
+        None => return ErrorResponse::unauthorized("Unauthorized"),
+    };
+
+    match ChatService::mark_conversation_read(&pool, &srv, user_id, path.into_inner(), input.up_to_message_id).await {
+        Ok(()) => ApiResponse::<()>::success_no_data("Conversation marked as read"),
+        Err(e) => read_error(e),
+    }
+}
+
+/// POST /api/chats/groups/{group_id}/read
+pub async fn mark_group_read(
+    pool: web::Data<DbPool>,
+    srv: web::Data<ChatServer>,
+    req: HttpRequest,
+    path: web::Path<i32>,
+    input: web::Json<ReadUpToInput>,
+) -> HttpResponse {
+    let user_id = match extract_user_id(&req) {
+        Some(id) => id,
+        None => return ErrorResponse::unauthorized("Unauthorized"),
+    };
+
+    match ChatService::mark_group_read(&pool, &srv, user_id, path.into_inner(), input.up_to_message_id).await {
+        Ok(()) => ApiResponse::<()>::success_no_data("Group marked as read"),
+        Err(e) => read_error(e),
+    }
+}
+
 /// POST /api/chats/groups
 pub async fn create_group(
     pool: web::Data<DbPool>,
+    srv: web::Data<ChatServer>,
     req: HttpRequest,
     input: web::Json<crate::modules::chat::model::CreateGroupInput>,
 ) -> HttpResponse {
@@ -55,8 +379,9 @@ pub async fn create_group(
         None => return ErrorResponse::unauthorized("Unauthorized"),
     };
 
-    match MessageRepository::create_group(
+    match ChatService::create_group(
         &pool, 
+        &srv,
         user_id, 
         &input.name, 
         input.description.clone(), 
@@ -83,11 +408,116 @@ pub async fn get_groups(
     }
 }
 
+/// GET /api/chats/groups/{group_id}/messages/{message_id}/seen-by
+pub async fn get_group_message_readers(
+    pool: web::Data<DbPool>,
+    req: HttpRequest,
+    path: web::Path<(i32, i32)>,
+) -> HttpResponse {
+    let user_id = match extract_user_id(&req) {
+        Some(id) => id,
+        None => return ErrorResponse::unauthorized("Unauthorized"),
+    };
+
+    let (group_id, message_id) = path.into_inner();
+
+    match MessageRepository::get_group_message_readers(&pool, group_id, message_id, user_id).await {
+        Ok(readers) => ApiResponse::success("Readers retrieved", readers),
+        Err(e) => {
+            let msg = e.to_string();
+            if msg.contains("not a member") {
+                ErrorResponse::forbidden(&msg)
+            } else if msg.contains("not found") {
+                ErrorResponse::not_found(&msg)
+            } else {
+                log::error!("Get readers error: {}", e);
+                ErrorResponse::internal_error("Failed to retrieve readers")
+            }
+        }
+    }
+}
+
+/// GET /api/chats
+/// Inbox: every DM conversation and group the user is in, most recently active first
+pub async fn get_chats(
+    pool: web::Data<DbPool>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let user_id = match extract_user_id(&req) {
+        Some(id) => id,
+        None => return ErrorResponse::unauthorized("Unauthorized"),
+    };
+
+    match MessageRepository::get_chat_summaries(&pool, user_id).await {
+        Ok(chats) => ApiResponse::success("Chats retrieved", chats),
+        Err(e) => {
+            log::error!("Get chats error: {}", e);
+            ErrorResponse::internal_error("Failed to retrieve chats")
+        }
+    }
+}
+
 pub fn configure(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/chats")
+            .route("", web::get().to(get_chats))
             .route("/{partner_id}/messages", web::get().to(get_chat_history))
+            .route("/{partner_id}/messages", web::post().to(send_message))
+            .route("/messages/{id}", web::patch().to(edit_message))
+            .route("/messages/{id}", web::delete().to(delete_message))
+            .route("/messages/{id}/read", web::post().to(mark_message_read))
+            .route("/typing", web::post().to(send_typing))
+            .route("/conversations/{conversation_id}/read", web::post().to(mark_conversation_read))
             .route("/groups", web::post().to(create_group))
             .route("/groups", web::get().to(get_groups))
+            .route("/groups/{group_id}/messages", web::get().to(get_group_history))
+            .route("/groups/{group_id}/messages", web::post().to(send_group_message))
+            .route("/groups/{group_id}/read", web::post().to(mark_group_read))
+            .route("/groups/{group_id}/messages/{message_id}/seen-by", web::get().to(get_group_message_readers))
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test, App};
+    use deadpool_postgres::Runtime;
+    use tokio_postgres::NoTls;
+    use super::*;
+    use crate::modules::auth::AuthMiddleware;
+
+    #[actix_rt::test]
+    async fn user_id_header_alone_is_not_authentication() {
+        // Never connected: every request here is refused before touching the database
+        let config = deadpool_postgres::Config {
+            dbname: Some("unused".to_string()),
+            ..Default::default()
+        };
+        let pool = config.create_pool(Some(Runtime::Tokio1), NoTls).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool))
+                .app_data(web::Data::new(ChatServer::new()))
+                .wrap(AuthMiddleware)
+                .service(web::scope("/api").configure(configure)),
+        ).await;
+
+        let requests = [
+            test::TestRequest::post().uri("/api/chats/2/messages").set_json(serde_json::json!({ "content": "hi" })),
+            test::TestRequest::post().uri("/api/chats/groups/1/messages").set_json(serde_json::json!({ "content": "hi" })),
+            test::TestRequest::patch().uri("/api/chats/messages/1").set_json(serde_json::json!({ "content": "hi" })),
+            test::TestRequest::delete().uri("/api/chats/messages/1?scope=everyone"),
+            test::TestRequest::post().uri("/api/chats/typing").set_json(serde_json::json!({ "conversation_id": 1, "is_typing": true })),
+            // Read receipts and seen-by are only checked against the authenticated user
+            test::TestRequest::post().uri("/api/chats/messages/1/read"),
+            test::TestRequest::post().uri("/api/chats/conversations/1/read").set_json(serde_json::json!({ "up_to_message_id": 1 })),
+            test::TestRequest::post().uri("/api/chats/groups/1/read").set_json(serde_json::json!({ "up_to_message_id": 1 })),
+            test::TestRequest::get().uri("/api/chats/groups/1/messages/1/seen-by"),
+        ];
+        for request in requests {
+            let request = request.insert_header(("X-User-Id", "1")).to_request();
+            let path = request.path().to_string();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{}", path);
+        }
+    }
+}
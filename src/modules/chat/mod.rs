@@ -1,6 +1,7 @@
 pub mod model;
 pub mod repository;
 pub mod controller;
+pub mod services;
 
-pub use repository::MessageRepository;
 pub use controller::configure;
+pub use services::ChatService;
@@ -1,7 +1,8 @@
 use serde::{Deserialize, Serialize};
 use chrono::{DateTime, Utc};
+use validator::Validate;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Message {
     pub id: i32,
     pub conversation_id: i32,
@@ -10,6 +11,103 @@ pub struct Message {
     pub message_type: String,
     pub sent_at: DateTime<Utc>,
     pub read_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_msg_id: Option<String>,
+    pub edited: bool,
+    pub deleted: bool,
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
+/// Public profile of a message's sender
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MessageSender {
+    pub id: i32,
+    pub username: String,
+    pub first_name: String,
+    pub last_name: String,
+}
+
+/// Group message with its sender's profile, for history views
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GroupMessageWithSender {
+    #[serde(flatten)]
+    pub message: Message,
+    pub group_id: i32,
+    pub sender: MessageSender,
+}
+
+/// A direct message reached one of its recipient's devices
+#[derive(Debug, Serialize, Clone)]
+pub struct DeliveryReceipt {
+    pub message_id: i32,
+    pub conversation_id: i32,
+    pub sender_id: i32,
+    pub delivered_at: DateTime<Utc>,
+}
+
+/// Direct messages in a conversation were read, up to and including `up_to_message_id`
+#[derive(Debug, Serialize, Clone)]
+pub struct ReadReceipt {
+    pub conversation_id: i32,
+    pub sender_id: i32,
+    pub reader_id: i32,
+    pub up_to_message_id: i32,
+    pub read_at: DateTime<Utc>,
+}
+
+/// A group member who has read a message
+#[derive(Debug, Serialize)]
+pub struct MessageReader {
+    #[serde(flatten)]
+    pub user: MessageSender,
+    pub read_at: DateTime<Utc>,
+}
+
+/// Where a history page starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageAnchor {
+    /// Most recent messages
+    Latest,
+    /// Messages older than this id
+    Before(i32),
+    /// Messages newer than this id
+    After(i32),
+    /// Messages surrounding (and including) this id, for jumping to a message
+    Around(i32),
+}
+
+/// A page of history, newest first
+#[derive(Debug)]
+pub struct HistoryPage<T> {
+    pub items: Vec<T>,
+    pub has_older: bool,
+    pub has_newer: bool,
+}
+
+/// The other side of an inbox entry
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatTarget {
+    Direct {
+        conversation_id: i32,
+        partner: MessageSender,
+    },
+    Group {
+        group_id: i32,
+        name: String,
+        avatar_url: Option<String>,
+    },
+}
+
+/// One inbox entry: a DM conversation or a group, with its latest message
+#[derive(Debug, Serialize)]
+pub struct ChatSummary {
+    #[serde(flatten)]
+    pub target: ChatTarget,
+    pub last_message: Option<Message>,
+    pub unread_count: i64,
+    /// Time of the last message, or when the chat was created if it has none
+    pub last_activity_at: DateTime<Utc>,
 }
 
 #[allow(dead_code)]
@@ -19,6 +117,43 @@ pub struct CreateMessageInput {
     pub content: String,
 }
 
+#[derive(Debug, Deserialize, Validate)]
+pub struct EditMessageInput {
+    #[validate(length(min = 1))]
+    pub content: String,
+    /// Set when editing a group message (group and DM ids are separate)
+    pub group_id: Option<i32>,
+}
+
+/// Body of the REST send endpoints
+#[derive(Debug, Deserialize)]
+pub struct SendMessageInput {
+    pub content: String,
+    /// Client-generated id; resending the same id returns the original message
+    pub client_msg_id: Option<String>,
+}
+
+/// Body of POST /api/chats/typing; set one of `conversation_id` or `group_id`
+#[derive(Debug, Deserialize)]
+pub struct TypingInput {
+    pub conversation_id: Option<i32>,
+    pub group_id: Option<i32>,
+    pub is_typing: bool,
+}
+
+/// Body of the conversation and group read endpoints
+#[derive(Debug, Deserialize)]
+pub struct ReadUpToInput {
+    pub up_to_message_id: i32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteMessageQuery {
+    /// "me" (default) hides the message for the caller, "everyone" tombstones it
+    pub scope: Option<String>,
+    pub group_id: Option<i32>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Group {
     pub id: i32,
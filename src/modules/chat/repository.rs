@@ -1,5 +1,14 @@
+use chrono::{DateTime, Duration, Utc};
+use tokio_postgres::Row;
 use crate::db::DbPool;
-use crate::modules::chat::model::{Message, Group};
+use deadpool_postgres::GenericClient;
+use tokio_postgres::types::ToSql;
+use crate::modules::chat::model::{
+    ChatSummary, ChatTarget, DeliveryReceipt, Message, Group, GroupMessageWithSender, HistoryPage, MessageReader, MessageSender,
+    PageAnchor, ReadReceipt,
+};
+use crate::modules::sync::model::SyncEventKind;
+use crate::modules::sync::SyncRepository;
 
 pub struct MessageRepository;
 
@@ -38,44 +47,73 @@ impl MessageRepository {
         Ok(row.get(0))
     }
 
-    /// Save a new message to database
+    /// Save a new message to database.
+    /// If `client_msg_id` was already used by this sender, the original message is
+    /// returned instead and the bool is false. Reusing it for another conversation is an error.
     pub async fn create_message(
         pool: &DbPool,
         sender_id: i32,
         recipient_id: i32,
         content: &str,
-    ) -> Result<Message, Box<dyn std::error::Error>> {
+        client_msg_id: Option<&str>,
+    ) -> Result<(Message, bool), Box<dyn std::error::Error>> {
         // 1. Get Conversation ID
         let conversation_id = Self::get_or_create_conversation(pool, sender_id, recipient_id).await?;
         
-        let client = pool.get().await?;
+        let mut client = pool.get().await?;
+        let transaction = client.transaction().await?;
 
-        // 2. Insert Message
-        let row = client.query_one(
-            "INSERT INTO messages (conversation_id, sender_id, content, message_type) 
-             VALUES ($1, $2, $3, 'text') 
-             RETURNING id, conversation_id, sender_id, content, message_type, sent_at, read_at",
-            &[&conversation_id, &sender_id, &content]
+        // 2. Insert Message (a retry with the same client id hits the conflict)
+        let row = transaction.query_opt(
+            &format!(
+                "INSERT INTO messages (conversation_id, sender_id, content, message_type, client_msg_id) 
+                 VALUES ($1, $2, $3, 'text', $4) 
+                 ON CONFLICT (sender_id, client_msg_id) WHERE client_msg_id IS NOT NULL DO NOTHING
+                 RETURNING {}",
+                MESSAGE_COLUMNS
+            ),
+            &[&conversation_id, &sender_id, &content, &client_msg_id]
         ).await?;
 
-        Ok(Message {
-            id: row.get(0),
-            conversation_id: row.get(1),
-            sender_id: row.get(2),
-            content: row.get(3),
-            message_type: row.get(4),
-            sent_at: row.get(5),
-            read_at: row.get(6),
-        })
+        let message = match row {
+            Some(row) => message_from_row(&row),
+            None => {
+                let existing = transaction.query_opt(
+                    &format!(
+                        "SELECT {} FROM messages WHERE sender_id = $1 AND client_msg_id = $2 AND conversation_id = $3",
+                        MESSAGE_COLUMNS
+                    ),
+                    &[&sender_id, &client_msg_id, &conversation_id]
+                ).await?;
+                return match existing {
+                    Some(row) => Ok((message_from_row(&row), false)),
+                    None => Err("client_msg_id is already used for another conversation".into()),
+                };
+            }
+        };
+
+        // 3. Record in both participants' sync feeds (sender's other devices need it too)
+        SyncRepository::record(
+            &transaction,
+            &[sender_id, recipient_id],
+            SyncEventKind::MessageCreated,
+            &serde_json::json!({ "message": &message }),
+        ).await?;
+
+        transaction.commit().await?;
+
+        Ok((message, true))
     }
-    /// Get message history between two users
+
+    /// Get message history between two users, as seen by `user1_id`
+    /// (messages they deleted for themselves are left out, tombstones are kept)
     pub async fn get_messages(
         pool: &DbPool,
         user1_id: i32,
         user2_id: i32,
+        anchor: PageAnchor,
         limit: i64,
-        offset: i64,
-    ) -> Result<Vec<Message>, Box<dyn std::error::Error>> {
+    ) -> Result<HistoryPage<Message>, Box<dyn std::error::Error>> {
         let client = pool.get().await?;
 
         // 1. Get conversation ID
@@ -90,31 +128,32 @@ impl MessageRepository {
 
         let conversation_id: i32 = match conv_row {
             Some(row) => row.get(0),
-            None => return Ok(vec![]),
+            None => return Ok(HistoryPage { items: vec![], has_older: false, has_newer: false }),
         };
 
         // 2. Fetch messages
-        let rows = client.query(
-            "SELECT id, conversation_id, sender_id, content, message_type, sent_at, read_at
-             FROM messages
-             WHERE conversation_id = $1
-             ORDER BY sent_at DESC
-             LIMIT $2 OFFSET $3",
-            &[&conversation_id, &limit, &offset]
+        let (rows, has_older, has_newer) = fetch_page(
+            &client,
+            &format!(
+                "SELECT {} FROM messages
+                 WHERE conversation_id = $1
+                   AND NOT EXISTS (
+                       SELECT 1 FROM hidden_messages h WHERE h.user_id = $2 AND h.message_id = messages.id
+                   )",
+                MESSAGE_COLUMNS
+            ),
+            "messages.id",
+            [&conversation_id, &user1_id],
+            anchor,
+            limit,
         ).await?;
 
-        let messages = rows.iter().map(|row| Message {
-            id: row.get(0),
-            conversation_id: row.get(1),
-            sender_id: row.get(2),
-            content: row.get(3),
-            message_type: row.get(4),
-            sent_at: row.get(5),
-            read_at: row.get(6),
-        }).collect();
-
-        Ok(messages)
-        }
+        Ok(HistoryPage {
+            items: rows.iter().map(message_from_row).collect(),
+            has_older,
+            has_newer,
+        })
+    }
 
     /// Create a new group with initial members
     pub async fn create_group(
@@ -154,15 +193,25 @@ impl MessageRepository {
             }
         }
 
-        transaction.commit().await?;
-
-        Ok(Group {
+        let group = Group {
             id: group_id,
             name: group_row.get(1),
             description: group_row.get(2),
             creator_id: group_row.get(3),
             created_at: group_row.get(4),
-        })
+        };
+
+        // 4. Let every member's devices pick up the new membership
+        SyncRepository::record_for_group(
+            &transaction,
+            group_id,
+            SyncEventKind::GroupCreated,
+            &serde_json::json!({ "group": &group }),
+        ).await?;
+
+        transaction.commit().await?;
+
+        Ok(group)
     }
 
     /// Get user's groups
@@ -192,41 +241,239 @@ impl MessageRepository {
         Ok(groups)
     }
 
-    /// Save a new group message
+    /// List the user's DM conversations and groups for the inbox, most recently active first.
+    /// Unread counts use `messages.read_at` for DMs and `group_members.last_read_message_id` for groups.
+    pub async fn get_chat_summaries(
+        pool: &DbPool,
+        user_id: i32,
+    ) -> Result<Vec<ChatSummary>, Box<dyn std::error::Error>> {
+        let client = pool.get().await?;
+
+        // The last message's columns come first, so message_from_row applies as-is;
+        // everything after them is aliased and read by name
+        let direct_rows = client.query(
+            &format!(
+                "SELECT lm.*, c.id AS chat_id, c.created_at AS chat_created_at,
+                        u.id AS partner_id, u.username AS partner_username,
+                        u.first_name AS partner_first_name, u.last_name AS partner_last_name,
+                        (SELECT COUNT(*) FROM messages m
+                         WHERE m.conversation_id = c.id AND m.sender_id <> $1
+                           AND m.read_at IS NULL AND NOT COALESCE(m.deleted, false)
+                           AND NOT EXISTS (
+                               SELECT 1 FROM hidden_messages h WHERE h.user_id = $1 AND h.message_id = m.id
+                           )) AS unread_count
+                 FROM conversations c
+                 JOIN users u ON u.id = CASE WHEN c.participant_1 = $1 THEN c.participant_2 ELSE c.participant_1 END
+                 LEFT JOIN LATERAL (
+                     SELECT {} FROM messages
+                     WHERE messages.conversation_id = c.id
+                       AND NOT EXISTS (
+                           SELECT 1 FROM hidden_messages h WHERE h.user_id = $1 AND h.message_id = messages.id
+                       )
+                     ORDER BY messages.id DESC LIMIT 1
+                 ) lm ON true
+                 WHERE c.participant_1 = $1 OR c.participant_2 = $1",
+                MESSAGE_COLUMNS
+            ),
+            &[&user_id]
+        ).await?;
+
+        let group_rows = client.query(
+            &format!(
+                "SELECT lm.*, g.id AS chat_id, g.created_at AS chat_created_at,
+                        g.name AS group_name, g.avatar_url AS group_avatar_url,
+                        (SELECT COUNT(*) FROM group_messages m
+                         WHERE m.group_id = g.id AND m.sender_id <> $1
+                           AND (gm.last_read_message_id IS NULL OR m.id > gm.last_read_message_id)
+                           AND NOT COALESCE(m.deleted, false)
+                           AND NOT EXISTS (
+                               SELECT 1 FROM hidden_messages h WHERE h.user_id = $1 AND h.group_message_id = m.id
+                           )) AS unread_count
+                 FROM group_members gm
+                 JOIN groups g ON g.id = gm.group_id
+                 LEFT JOIN LATERAL (
+                     SELECT {} FROM group_messages
+                     WHERE group_messages.group_id = g.id
+                       AND NOT EXISTS (
+                           SELECT 1 FROM hidden_messages h
+                           WHERE h.user_id = $1 AND h.group_message_id = group_messages.id
+                       )
+                     ORDER BY group_messages.id DESC LIMIT 1
+                 ) lm ON true
+                 WHERE gm.user_id = $1",
+                GROUP_MESSAGE_COLUMNS
+            ),
+            &[&user_id]
+        ).await?;
+
+        let last_message = |row: &Row| {
+            row.get::<_, Option<i32>>(0).map(|_| message_from_row(row))
+        };
+
+        let mut chats: Vec<ChatSummary> = direct_rows.iter().map(|row| {
+            let last_message = last_message(row);
+            let created_at: DateTime<Utc> = row.get("chat_created_at");
+            ChatSummary {
+                target: ChatTarget::Direct {
+                    conversation_id: row.get("chat_id"),
+                    partner: MessageSender {
+                        id: row.get("partner_id"),
+                        username: row.get("partner_username"),
+                        first_name: row.get("partner_first_name"),
+                        last_name: row.get("partner_last_name"),
+                    },
+                },
+                last_activity_at: last_message.as_ref().map_or(created_at, |m| m.sent_at),
+                last_message,
+                unread_count: row.get("unread_count"),
+            }
+        }).collect();
+
+        chats.extend(group_rows.iter().map(|row| {
+            let last_message = last_message(row);
+            let created_at: DateTime<Utc> = row.get("chat_created_at");
+            ChatSummary {
+                target: ChatTarget::Group {
+                    group_id: row.get("chat_id"),
+                    name: row.get("group_name"),
+                    avatar_url: row.get("group_avatar_url"),
+                },
+                last_activity_at: last_message.as_ref().map_or(created_at, |m| m.sent_at),
+                last_message,
+                unread_count: row.get("unread_count"),
+            }
+        }));
+
+        chats.sort_by_key(|chat| std::cmp::Reverse(chat.last_activity_at));
+
+        Ok(chats)
+    }
+
+    /// Save a new group message.
+    /// Like `create_message`, a repeated `client_msg_id` returns the original (false),
+    /// and reusing it for another group is an error.
     pub async fn create_group_message(
         pool: &DbPool,
         sender_id: i32,
         group_id: i32,
         content: &str,
-    ) -> Result<Message, Box<dyn std::error::Error>> {
-        let client = pool.get().await?;
+        client_msg_id: Option<&str>,
+    ) -> Result<(Message, bool), Box<dyn std::error::Error>> {
+        let mut client = pool.get().await?;
+        let transaction = client.transaction().await?;
 
         // Check if sender is a member (basic security)
-        let member_check = client.query_opt(
+        if !Self::is_group_member(&transaction, group_id, sender_id).await? {
+            return Err("User is not a member of this group".into());
+        }
+
+        let row = transaction.query_opt(
+            &format!(
+                "INSERT INTO group_messages (group_id, sender_id, content, message_type, client_msg_id)
+                 VALUES ($1, $2, $3, 'text', $4)
+                 ON CONFLICT (sender_id, client_msg_id) WHERE client_msg_id IS NOT NULL DO NOTHING
+                 RETURNING {}",
+                GROUP_MESSAGE_COLUMNS
+            ),
+            &[&group_id, &sender_id, &content, &client_msg_id]
+        ).await?;
+
+        let message = match row {
+            Some(row) => message_from_row(&row),
+            None => {
+                let existing = transaction.query_opt(
+                    &format!(
+                        "SELECT {} FROM group_messages WHERE sender_id = $1 AND client_msg_id = $2 AND group_id = $3",
+                        GROUP_MESSAGE_COLUMNS
+                    ),
+                    &[&sender_id, &client_msg_id, &group_id]
+                ).await?;
+                return match existing {
+                    Some(row) => Ok((message_from_row(&row), false)),
+                    None => Err("client_msg_id is already used for another group".into()),
+                };
+            }
+        };
+
+        // Posting implies the sender has read everything up to their own message
+        transaction.execute(
+            "UPDATE group_members SET last_read_message_id = $3, last_read_at = NOW()
+             WHERE group_id = $1 AND user_id = $2
+               AND (last_read_message_id IS NULL OR last_read_message_id < $3)",
+            &[&group_id, &sender_id, &message.id]
+        ).await?;
+
+        SyncRepository::record_for_group(
+            &transaction,
+            group_id,
+            SyncEventKind::GroupMessageCreated,
+            &serde_json::json!({ "group_id": group_id, "message": &message }),
+        ).await?;
+
+        transaction.commit().await?;
+
+        Ok((message, true))
+    }
+
+    /// Check whether a user belongs to a group
+    pub async fn is_group_member<C: GenericClient>(
+        client: &C,
+        group_id: i32,
+        user_id: i32,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let row = client.query_opt(
             "SELECT 1 FROM group_members WHERE group_id = $1 AND user_id = $2",
-            &[&group_id, &sender_id]
+            &[&group_id, &user_id]
         ).await?;
 
-        if member_check.is_none() {
+        Ok(row.is_some())
+    }
+
+    /// Get group message history with sender profiles, as seen by `user_id`
+    pub async fn get_group_messages(
+        pool: &DbPool,
+        group_id: i32,
+        user_id: i32,
+        anchor: PageAnchor,
+        limit: i64,
+    ) -> Result<HistoryPage<GroupMessageWithSender>, Box<dyn std::error::Error>> {
+        let client = pool.get().await?;
+
+        if !Self::is_group_member(&client, group_id, user_id).await? {
             return Err("User is not a member of this group".into());
         }
 
-        let row = client.query_one(
-            "INSERT INTO group_messages (group_id, sender_id, content, message_type)
-             VALUES ($1, $2, $3, 'text')
-             RETURNING id, group_id, sender_id, content, message_type, sent_at",
-            &[&group_id, &sender_id, &content]
+        let (rows, has_older, has_newer) = fetch_page(
+            &client,
+            &format!(
+                "SELECT {}, u.id, u.username, u.first_name, u.last_name
+                 FROM group_messages
+                 JOIN users u ON u.id = group_messages.sender_id
+                 WHERE group_messages.group_id = $1
+                   AND NOT EXISTS (
+                       SELECT 1 FROM hidden_messages h
+                       WHERE h.user_id = $2 AND h.group_message_id = group_messages.id
+                   )",
+                GROUP_MESSAGE_COLUMNS_QUALIFIED
+            ),
+            "group_messages.id",
+            [&group_id, &user_id],
+            anchor,
+            limit,
         ).await?;
 
-        Ok(Message {
-            id: row.get(0),
-            conversation_id: 0, // 0 indicates group message in this unified struct, or handle separately
-            sender_id: row.get(2),
-            content: row.get(3),
-            message_type: row.get(4),
-            sent_at: row.get(5),
-            read_at: None, // Group messages read status is complex (many users), skipping for now
-        })
+        let items = rows.iter().map(|row| GroupMessageWithSender {
+            message: message_from_row(row),
+            group_id,
+            sender: MessageSender {
+                id: row.get(11),
+                username: row.get(12),
+                first_name: row.get(13),
+                last_name: row.get(14),
+            },
+        }).collect();
+
+        Ok(HistoryPage { items, has_older, has_newer })
     }
 
     /// Get all user IDs in a group
@@ -270,28 +517,663 @@ impl MessageRepository {
         Ok(None)
     }
 
-    /// Mark a message as read and return the sender_id (to notify them)
+    /// Mark a single direct message as read by the other participant of its conversation.
+    /// Returns None if it was already read.
     pub async fn mark_message_read(
         pool: &DbPool,
         message_id: i32,
-        _reader_id: i32, // potentially used for verification
-    ) -> Result<Option<i32>, Box<dyn std::error::Error>> {
+        reader_id: i32,
+    ) -> Result<Option<ReadReceipt>, Box<dyn std::error::Error>> {
+        let mut client = pool.get().await?;
+        let transaction = client.transaction().await?;
+
+        // Non-participants get the same answer as a missing message
+        let row = transaction.query_opt(
+            "SELECT m.sender_id, m.conversation_id, m.read_at
+             FROM messages m
+             JOIN conversations c ON c.id = m.conversation_id
+             WHERE m.id = $1 AND (c.participant_1 = $2 OR c.participant_2 = $2)
+             FOR UPDATE OF m",
+            &[&message_id, &reader_id]
+        ).await?;
+
+        let (sender_id, conversation_id, read_at): (i32, i32, Option<DateTime<Utc>>) = match row {
+            Some(row) => (row.get(0), row.get(1), row.get(2)),
+            None => return Err("Message not found".into()),
+        };
+
+        if sender_id == reader_id {
+            return Err("Cannot mark your own message as read".into());
+        }
+        if read_at.is_some() {
+            return Ok(None);
+        }
+
+        // Reading a message implies it was delivered
+        let read_at: DateTime<Utc> = transaction.query_one(
+            "UPDATE messages
+             SET read_at = NOW(), delivered_at = COALESCE(delivered_at, NOW())
+             WHERE id = $1
+             RETURNING read_at",
+            &[&message_id]
+        ).await?.get(0);
+
+        let receipt = ReadReceipt {
+            conversation_id,
+            sender_id,
+            reader_id,
+            up_to_message_id: message_id,
+            read_at,
+        };
+
+        SyncRepository::record(
+            &transaction,
+            &[sender_id, reader_id],
+            SyncEventKind::MessageRead,
+            &serde_json::json!({
+                "message_id": message_id,
+                "conversation_id": conversation_id,
+                "reader_id": reader_id,
+                "read_at": read_at,
+            }),
+        ).await?;
+
+        transaction.commit().await?;
+
+        Ok(Some(receipt))
+    }
+
+    /// Mark every message the partner sent in a conversation, up to and including
+    /// `up_to_message_id`, as read. Returns None if there was nothing unread.
+    pub async fn mark_conversation_read(
+        pool: &DbPool,
+        conversation_id: i32,
+        reader_id: i32,
+        up_to_message_id: i32,
+    ) -> Result<Option<ReadReceipt>, Box<dyn std::error::Error>> {
+        let sender_id = match Self::get_conversation_partner(pool, conversation_id, reader_id).await? {
+            Some(partner_id) => partner_id,
+            None => return Err("Conversation not found".into()),
+        };
+
+        let mut client = pool.get().await?;
+        let transaction = client.transaction().await?;
+
+        let in_conversation = transaction.query_opt(
+            "SELECT 1 FROM messages WHERE id = $1 AND conversation_id = $2",
+            &[&up_to_message_id, &conversation_id]
+        ).await?;
+        if in_conversation.is_none() {
+            return Err("Message not found".into());
+        }
+
+        let rows = transaction.query(
+            "UPDATE messages
+             SET read_at = NOW(), delivered_at = COALESCE(delivered_at, NOW())
+             WHERE conversation_id = $1 AND sender_id = $2 AND id <= $3 AND read_at IS NULL
+             RETURNING read_at",
+            &[&conversation_id, &sender_id, &up_to_message_id]
+        ).await?;
+
+        let read_at: DateTime<Utc> = match rows.first() {
+            Some(row) => row.get(0),
+            None => return Ok(None),
+        };
+
+        let receipt = ReadReceipt {
+            conversation_id,
+            sender_id,
+            reader_id,
+            up_to_message_id,
+            read_at,
+        };
+
+        SyncRepository::record(
+            &transaction,
+            &[sender_id, reader_id],
+            SyncEventKind::ConversationRead,
+            &serde_json::json!(receipt),
+        ).await?;
+
+        transaction.commit().await?;
+
+        Ok(Some(receipt))
+    }
+
+    /// Stamp `delivered_at` on the given direct messages if they were sent to `recipient_id`.
+    /// Returns a receipt for each message that was newly stamped.
+    pub async fn mark_delivered(
+        pool: &DbPool,
+        recipient_id: i32,
+        message_ids: &[i32],
+    ) -> Result<Vec<DeliveryReceipt>, Box<dyn std::error::Error>> {
+        let mut client = pool.get().await?;
+        let transaction = client.transaction().await?;
+
+        let rows = transaction.query(
+            "UPDATE messages m
+             SET delivered_at = NOW()
+             FROM conversations c
+             WHERE c.id = m.conversation_id
+               AND (c.participant_1 = $1 OR c.participant_2 = $1)
+               AND m.sender_id <> $1
+               AND m.delivered_at IS NULL
+               AND m.id = ANY($2)
+             RETURNING m.id, m.conversation_id, m.sender_id, m.delivered_at",
+            &[&recipient_id, &message_ids]
+        ).await?;
+
+        let receipts: Vec<DeliveryReceipt> = rows.iter().map(|row| DeliveryReceipt {
+            message_id: row.get(0),
+            conversation_id: row.get(1),
+            sender_id: row.get(2),
+            delivered_at: row.get(3),
+        }).collect();
+
+        // Only the sender's devices care about delivery. Recorded in sender order, as
+        // each sender's sync counter stays locked until commit.
+        let mut by_sender: Vec<&DeliveryReceipt> = receipts.iter().collect();
+        by_sender.sort_by_key(|receipt| receipt.sender_id);
+        for receipt in by_sender {
+            SyncRepository::record(
+                &transaction,
+                &[receipt.sender_id],
+                SyncEventKind::MessageDelivered,
+                &serde_json::json!(receipt),
+            ).await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(receipts)
+    }
+
+    /// Move a member's read watermark (`group_members.last_read_message_id`) up to a group
+    /// message. Returns when it was read, or None if it was already at or past that message.
+    pub async fn mark_group_read(
+        pool: &DbPool,
+        group_id: i32,
+        user_id: i32,
+        up_to_message_id: i32,
+    ) -> Result<Option<DateTime<Utc>>, Box<dyn std::error::Error>> {
+        let mut client = pool.get().await?;
+        let transaction = client.transaction().await?;
+
+        if !Self::is_group_member(&transaction, group_id, user_id).await? {
+            return Err("User is not a member of this group".into());
+        }
+
+        let found = transaction.query_opt(
+            "SELECT 1 FROM group_messages WHERE id = $1 AND group_id = $2",
+            &[&up_to_message_id, &group_id]
+        ).await?;
+        if found.is_none() {
+            return Err("Message not found".into());
+        }
+
+        // Watermarks only move forward
+        let read_at: DateTime<Utc> = match transaction.query_opt(
+            "UPDATE group_members SET last_read_message_id = $3, last_read_at = NOW()
+             WHERE group_id = $1 AND user_id = $2
+               AND (last_read_message_id IS NULL OR last_read_message_id < $3)
+             RETURNING last_read_at",
+            &[&group_id, &user_id, &up_to_message_id]
+        ).await? {
+            Some(row) => row.get(0),
+            None => return Ok(None),
+        };
+
+        SyncRepository::record_for_group(
+            &transaction,
+            group_id,
+            SyncEventKind::GroupRead,
+            &serde_json::json!({
+                "group_id": group_id,
+                "reader_id": user_id,
+                "up_to_message_id": up_to_message_id,
+                "read_at": read_at,
+            }),
+        ).await?;
+
+        transaction.commit().await?;
+
+        Ok(Some(read_at))
+    }
+
+    /// Members (other than the sender) whose read watermark has reached a group message
+    pub async fn get_group_message_readers(
+        pool: &DbPool,
+        group_id: i32,
+        message_id: i32,
+        user_id: i32,
+    ) -> Result<Vec<MessageReader>, Box<dyn std::error::Error>> {
         let client = pool.get().await?;
 
-        // Update read_at if not already set
-        // return sender_id so we can notify them
-        let row = client.query_opt(
-            "UPDATE messages 
-             SET read_at = NOW() 
-             WHERE id = $1 AND read_at IS NULL 
-             RETURNING sender_id",
+        if !Self::is_group_member(&client, group_id, user_id).await? {
+            return Err("User is not a member of this group".into());
+        }
+
+        let sender_id: i32 = match client.query_opt(
+            "SELECT sender_id FROM group_messages WHERE id = $1 AND group_id = $2",
+            &[&message_id, &group_id]
+        ).await? {
+            Some(row) => row.get(0),
+            None => return Err("Message not found".into()),
+        };
+
+        let rows = client.query(
+            "SELECT u.id, u.username, u.first_name, u.last_name, gm.last_read_at
+             FROM group_members gm
+             JOIN users u ON u.id = gm.user_id
+             WHERE gm.group_id = $1 AND gm.user_id <> $2 AND gm.last_read_message_id >= $3
+             ORDER BY gm.last_read_at ASC",
+            &[&group_id, &sender_id, &message_id]
+        ).await?;
+
+        Ok(rows.iter().map(|row| MessageReader {
+            user: MessageSender {
+                id: row.get(0),
+                username: row.get(1),
+                first_name: row.get(2),
+                last_name: row.get(3),
+            },
+            read_at: row.get(4),
+        }).collect())
+    }
+
+    /// Edit a direct message, keeping the previous content in message_edits.
+    /// Returns the updated message and both participant ids.
+    pub async fn edit_message(
+        pool: &DbPool,
+        message_id: i32,
+        editor_id: i32,
+        content: &str,
+        edit_window: Duration,
+    ) -> Result<(Message, Vec<i32>), Box<dyn std::error::Error>> {
+        let mut client = pool.get().await?;
+        let transaction = client.transaction().await?;
+
+        // Lock the row so concurrent edits record history in order
+        let row = transaction.query_opt(
+            "SELECT m.sender_id, m.sent_at, m.content, c.participant_1, c.participant_2
+             FROM messages m
+             JOIN conversations c ON c.id = m.conversation_id
+             WHERE m.id = $1 AND NOT COALESCE(m.deleted, false)
+             FOR UPDATE OF m",
             &[&message_id]
+        ).await?.ok_or("Message not found")?;
+
+        let sender_id: i32 = row.get(0);
+        let sent_at: DateTime<Utc> = row.get(1);
+        let previous_content: String = row.get(2);
+        let participants: Vec<i32> = vec![row.get(3), row.get(4)];
+
+        Self::check_edit_allowed(sender_id, editor_id, sent_at, edit_window)?;
+
+        transaction.execute(
+            "INSERT INTO message_edits (message_id, editor_id, previous_content) VALUES ($1, $2, $3)",
+            &[&message_id, &editor_id, &previous_content]
+        ).await?;
+
+        let row = transaction.query_one(
+            &format!(
+                "UPDATE messages SET content = $2, edited = true WHERE id = $1 RETURNING {}",
+                MESSAGE_COLUMNS
+            ),
+            &[&message_id, &content]
+        ).await?;
+        let message = message_from_row(&row);
+
+        SyncRepository::record(
+            &transaction,
+            &participants,
+            SyncEventKind::MessageEdited,
+            &serde_json::json!({ "message": &message }),
+        ).await?;
+
+        transaction.commit().await?;
+
+        Ok((message, participants))
+    }
+
+    /// Edit a group message, keeping the previous content in message_edits
+    pub async fn edit_group_message(
+        pool: &DbPool,
+        group_id: i32,
+        message_id: i32,
+        editor_id: i32,
+        content: &str,
+        edit_window: Duration,
+    ) -> Result<Message, Box<dyn std::error::Error>> {
+        let mut client = pool.get().await?;
+        let transaction = client.transaction().await?;
+
+        let row = transaction.query_opt(
+            "SELECT sender_id, sent_at, content FROM group_messages
+             WHERE id = $1 AND group_id = $2 AND NOT COALESCE(deleted, false)
+             FOR UPDATE",
+            &[&message_id, &group_id]
+        ).await?.ok_or("Message not found")?;
+
+        let sender_id: i32 = row.get(0);
+        let sent_at: DateTime<Utc> = row.get(1);
+        let previous_content: String = row.get(2);
+
+        Self::check_edit_allowed(sender_id, editor_id, sent_at, edit_window)?;
+
+        transaction.execute(
+            "INSERT INTO message_edits (group_message_id, editor_id, previous_content) VALUES ($1, $2, $3)",
+            &[&message_id, &editor_id, &previous_content]
+        ).await?;
+
+        let row = transaction.query_one(
+            &format!(
+                "UPDATE group_messages SET content = $2, edited = true WHERE id = $1 RETURNING {}",
+                GROUP_MESSAGE_COLUMNS
+            ),
+            &[&message_id, &content]
         ).await?;
+        let message = message_from_row(&row);
 
-        match row {
-            Some(r) => Ok(Some(r.get(0))),
-            None => Ok(None) // Already read or not found
+        SyncRepository::record_for_group(
+            &transaction,
+            group_id,
+            SyncEventKind::MessageEdited,
+            &serde_json::json!({ "group_id": group_id, "message": &message }),
+        ).await?;
+
+        transaction.commit().await?;
+
+        Ok(message)
+    }
+
+    /// Delete a direct message for both participants: the row stays as a tombstone
+    /// with its content (and edit history) wiped. Returns both participant ids.
+    pub async fn delete_message_for_everyone(
+        pool: &DbPool,
+        message_id: i32,
+        user_id: i32,
+    ) -> Result<(i32, Vec<i32>), Box<dyn std::error::Error>> {
+        let mut client = pool.get().await?;
+        let transaction = client.transaction().await?;
+
+        let row = transaction.query_opt(
+            "SELECT m.sender_id, m.conversation_id, c.participant_1, c.participant_2
+             FROM messages m
+             JOIN conversations c ON c.id = m.conversation_id
+             WHERE m.id = $1 AND NOT COALESCE(m.deleted, false)
+             FOR UPDATE OF m",
+            &[&message_id]
+        ).await?.ok_or("Message not found")?;
+
+        let sender_id: i32 = row.get(0);
+        let conversation_id: i32 = row.get(1);
+        let participants: Vec<i32> = vec![row.get(2), row.get(3)];
+
+        if sender_id != user_id {
+            return Err("Only the sender can delete this message for everyone".into());
+        }
+
+        transaction.execute(
+            "UPDATE messages SET deleted = true, content = '' WHERE id = $1",
+            &[&message_id]
+        ).await?;
+        transaction.execute("DELETE FROM message_edits WHERE message_id = $1", &[&message_id]).await?;
+        SyncRepository::scrub_message(&transaction, message_id, None).await?;
+
+        SyncRepository::record(
+            &transaction,
+            &participants,
+            SyncEventKind::MessageDeleted,
+            &serde_json::json!({
+                "message_id": message_id,
+                "conversation_id": conversation_id,
+                "for_everyone": true,
+            }),
+        ).await?;
+
+        transaction.commit().await?;
+
+        Ok((conversation_id, participants))
+    }
+
+    /// Delete a group message for all members (tombstone, content wiped)
+    pub async fn delete_group_message_for_everyone(
+        pool: &DbPool,
+        group_id: i32,
+        message_id: i32,
+        user_id: i32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut client = pool.get().await?;
+        let transaction = client.transaction().await?;
+
+        let row = transaction.query_opt(
+            "SELECT sender_id FROM group_messages
+             WHERE id = $1 AND group_id = $2 AND NOT COALESCE(deleted, false)
+             FOR UPDATE",
+            &[&message_id, &group_id]
+        ).await?.ok_or("Message not found")?;
+
+        let sender_id: i32 = row.get(0);
+        if sender_id != user_id {
+            return Err("Only the sender can delete this message for everyone".into());
+        }
+
+        transaction.execute(
+            "UPDATE group_messages SET deleted = true, content = '' WHERE id = $1",
+            &[&message_id]
+        ).await?;
+        transaction.execute("DELETE FROM message_edits WHERE group_message_id = $1", &[&message_id]).await?;
+        SyncRepository::scrub_message(&transaction, message_id, Some(group_id)).await?;
+
+        SyncRepository::record_for_group(
+            &transaction,
+            group_id,
+            SyncEventKind::MessageDeleted,
+            &serde_json::json!({
+                "message_id": message_id,
+                "group_id": group_id,
+                "for_everyone": true,
+            }),
+        ).await?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    /// Hide a direct message for one participant only. Returns its conversation id.
+    pub async fn hide_message(
+        pool: &DbPool,
+        message_id: i32,
+        user_id: i32,
+    ) -> Result<i32, Box<dyn std::error::Error>> {
+        let mut client = pool.get().await?;
+        let transaction = client.transaction().await?;
+
+        let row = transaction.query_opt(
+            "SELECT m.conversation_id
+             FROM messages m
+             JOIN conversations c ON c.id = m.conversation_id
+             WHERE m.id = $1 AND (c.participant_1 = $2 OR c.participant_2 = $2)",
+            &[&message_id, &user_id]
+        ).await?.ok_or("Message not found")?;
+        let conversation_id: i32 = row.get(0);
+
+        transaction.execute(
+            "INSERT INTO hidden_messages (user_id, message_id) VALUES ($1, $2)
+             ON CONFLICT DO NOTHING",
+            &[&user_id, &message_id]
+        ).await?;
+
+        SyncRepository::record(
+            &transaction,
+            &[user_id],
+            SyncEventKind::MessageDeleted,
+            &serde_json::json!({
+                "message_id": message_id,
+                "conversation_id": conversation_id,
+                "for_everyone": false,
+            }),
+        ).await?;
+
+        transaction.commit().await?;
+
+        Ok(conversation_id)
+    }
+
+    /// Hide a group message for one member only
+    pub async fn hide_group_message(
+        pool: &DbPool,
+        group_id: i32,
+        message_id: i32,
+        user_id: i32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut client = pool.get().await?;
+        let transaction = client.transaction().await?;
+
+        let found = transaction.query_opt(
+            "SELECT 1 FROM group_messages gm
+             JOIN group_members m ON m.group_id = gm.group_id AND m.user_id = $3
+             WHERE gm.id = $1 AND gm.group_id = $2",
+            &[&message_id, &group_id, &user_id]
+        ).await?;
+
+        if found.is_none() {
+            return Err("Message not found".into());
+        }
+
+        transaction.execute(
+            "INSERT INTO hidden_messages (user_id, group_message_id) VALUES ($1, $2)
+             ON CONFLICT DO NOTHING",
+            &[&user_id, &message_id]
+        ).await?;
+
+        SyncRepository::record(
+            &transaction,
+            &[user_id],
+            SyncEventKind::MessageDeleted,
+            &serde_json::json!({
+                "message_id": message_id,
+                "group_id": group_id,
+                "for_everyone": false,
+            }),
+        ).await?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    fn check_edit_allowed(
+        sender_id: i32,
+        editor_id: i32,
+        sent_at: DateTime<Utc>,
+        edit_window: Duration,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if sender_id != editor_id {
+            return Err("Only the sender can edit this message".into());
+        }
+        if Utc::now() - sent_at > edit_window {
+            return Err("Edit window has expired".into());
+        }
+        Ok(())
+    }
+}
+
+const MESSAGE_COLUMNS: &str =
+    "id, conversation_id, sender_id, content, message_type, sent_at, read_at, client_msg_id, \
+     COALESCE(edited, false), COALESCE(deleted, false), delivered_at";
+
+// Group messages share the Message struct: conversation_id 0 marks a group message,
+// and read/delivery status is per member so read_at and delivered_at are always NULL here
+const GROUP_MESSAGE_COLUMNS: &str =
+    "id, 0 AS conversation_id, sender_id, content, message_type, sent_at, NULL::TIMESTAMPTZ AS read_at, client_msg_id, \
+     COALESCE(edited, false), COALESCE(deleted, false), NULL::TIMESTAMPTZ AS delivered_at";
+
+// Same as GROUP_MESSAGE_COLUMNS, for queries that join other tables
+const GROUP_MESSAGE_COLUMNS_QUALIFIED: &str =
+    "group_messages.id, 0 AS conversation_id, group_messages.sender_id, group_messages.content, \
+     group_messages.message_type, group_messages.sent_at, NULL::TIMESTAMPTZ AS read_at, \
+     group_messages.client_msg_id, COALESCE(group_messages.edited, false), COALESCE(group_messages.deleted, false), \
+     NULL::TIMESTAMPTZ AS delivered_at";
+
+/// Run a keyset-paginated history query.
+/// `base_query` selects the candidate rows using `$1` and `$2`; the page bounds are
+/// appended against `id_column`. Rows come back newest first, along with whether
+/// older / newer rows exist beyond the page.
+async fn fetch_page<C: GenericClient>(
+    client: &C,
+    base_query: &str,
+    id_column: &str,
+    base_params: [&(dyn ToSql + Sync); 2],
+    anchor: PageAnchor,
+    limit: i64,
+) -> Result<(Vec<Row>, bool, bool), Box<dyn std::error::Error>> {
+    let older_query = format!("{} AND {} < $3 ORDER BY {} DESC LIMIT $4", base_query, id_column, id_column);
+    let newer_query = format!("{} AND {} > $3 ORDER BY {} ASC LIMIT $4", base_query, id_column, id_column);
+    let target_and_older_query =
+        format!("{} AND {} <= $3 ORDER BY {} DESC LIMIT $4", base_query, id_column, id_column);
+    let target_and_newer_query =
+        format!("{} AND {} >= $3 ORDER BY {} ASC LIMIT $4", base_query, id_column, id_column);
+    let [p1, p2] = base_params;
+
+    match anchor {
+        PageAnchor::Latest | PageAnchor::Before(_) => {
+            let before = match anchor {
+                PageAnchor::Before(id) => id,
+                _ => i32::MAX,
+            };
+            let mut rows = client.query(&older_query, &[p1, p2, &before, &(limit + 1)]).await?;
+            let has_older = rows.len() as i64 > limit;
+            rows.truncate(limit as usize);
+            // The anchor is excluded from the page, so it and anything after it are "newer"
+            let has_newer = match anchor {
+                PageAnchor::Before(id) => !client.query(&target_and_newer_query, &[p1, p2, &id, &1i64]).await?.is_empty(),
+                _ => false,
+            };
+            Ok((rows, has_older, has_newer))
+        }
+        PageAnchor::After(after) => {
+            let mut rows = client.query(&newer_query, &[p1, p2, &after, &(limit + 1)]).await?;
+            let has_newer = rows.len() as i64 > limit;
+            rows.truncate(limit as usize);
+            rows.reverse();
+            let has_older = !client.query(&target_and_older_query, &[p1, p2, &after, &1i64]).await?.is_empty();
+            Ok((rows, has_older, has_newer))
+        }
+        PageAnchor::Around(id) => {
+            // Split the page around the target; the target itself counts as "older"
+            let older_limit = limit - limit / 2;
+            let newer_limit = limit / 2;
+
+            let mut older = client.query(&target_and_older_query, &[p1, p2, &id, &(older_limit + 1)]).await?;
+            let has_older = older.len() as i64 > older_limit;
+            older.truncate(older_limit as usize);
+
+            let mut newer = client.query(&newer_query, &[p1, p2, &id, &(newer_limit + 1)]).await?;
+            let has_newer = newer.len() as i64 > newer_limit;
+            newer.truncate(newer_limit as usize);
+            newer.reverse();
+
+            newer.extend(older);
+            Ok((newer, has_older, has_newer))
         }
     }
 }
 
+fn message_from_row(row: &Row) -> Message {
+    Message {
+        id: row.get(0),
+        conversation_id: row.get(1),
+        sender_id: row.get(2),
+        content: row.get(3),
+        message_type: row.get(4),
+        sent_at: row.get(5),
+        read_at: row.get(6),
+        client_msg_id: row.get(7),
+        edited: row.get(8),
+        deleted: row.get(9),
+        delivered_at: row.get(10),
+    }
+}
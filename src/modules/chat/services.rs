@@ -0,0 +1,401 @@
+use chrono::Duration;
+use std::env;
+use tokio::sync::mpsc;
+use crate::db::DbPool;
+use crate::modules::auth::repository::AuthRepository;
+use crate::modules::chat::model::{Group, Message};
+use crate::modules::chat::repository::MessageRepository;
+use crate::modules::ws::type_def::{ServerEvent, WsClient};
+use crate::modules::ws::typing::TypingTarget;
+use crate::modules::ws::ChatServer;
+
+/// Longest `client_msg_id` accepted, in characters (the column is VARCHAR(64))
+const MAX_CLIENT_MSG_ID_LENGTH: usize = 64;
+
+/// Chat operations shared by the REST and WebSocket entry points
+pub struct ChatService;
+
+impl ChatService {
+    /// How long after sending a message its sender may still edit it (MESSAGE_EDIT_WINDOW)
+    pub fn edit_window() -> Duration {
+        let secs = env::var("MESSAGE_EDIT_WINDOW")
+            .unwrap_or_else(|_| "900".to_string())
+            .parse::<i64>()
+            .unwrap_or(900);
+        Duration::seconds(secs)
+    }
+
+    /// Reject empty message content or content over `max_length` characters
+    pub fn check_content(content: &str, max_length: usize) -> Result<(), Box<dyn std::error::Error>> {
+        if content.trim().is_empty() {
+            return Err("Message content cannot be empty".into());
+        }
+        if content.chars().count() > max_length {
+            return Err(format!("Message content is limited to {} characters", max_length).into());
+        }
+        Ok(())
+    }
+
+    /// Reject a `client_msg_id` too long to store
+    fn check_client_msg_id(client_msg_id: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+        if client_msg_id.is_some_and(|id| id.chars().count() > MAX_CLIENT_MSG_ID_LENGTH) {
+            return Err(format!("client_msg_id cannot be longer than {} characters", MAX_CLIENT_MSG_ID_LENGTH).into());
+        }
+        Ok(())
+    }
+
+    /// Send a direct message: save it and queue it for the recipient's devices; delivery
+    /// is confirmed once a device's writer has sent it (see `delivery_confirmations`).
+    /// `origin` is the connection the send came from, which gets the `Ack`; REST callers
+    /// pass None and answer with the message.
+    /// Retrying a `client_msg_id` returns the original message without sending it again.
+    pub async fn send_direct(
+        pool: &DbPool,
+        srv: &ChatServer,
+        sender_id: i32,
+        to_user_id: i32,
+        content: &str,
+        client_msg_id: Option<String>,
+        origin: Option<&WsClient>,
+    ) -> Result<Message, Box<dyn std::error::Error>> {
+        Self::check_content(content, srv.max_content_length())?;
+        Self::check_client_msg_id(client_msg_id.as_deref())?;
+        if to_user_id == sender_id {
+            return Err("Cannot send a message to yourself".into());
+        }
+        if AuthRepository::find_by_id(pool, to_user_id).await?.is_none() {
+            return Err("Recipient not found".into());
+        }
+
+        log::info!("Message from {} to {}: {}", sender_id, to_user_id, content);
+
+        let (message, created) = MessageRepository::create_message(
+            pool, sender_id, to_user_id, content, client_msg_id.as_deref(),
+        ).await?;
+        let ack = ServerEvent::Ack {
+            client_msg_id,
+            message_id: message.id,
+            sent_at: message.sent_at,
+        };
+
+        // A retried send: just ack the original, the recipient already has it
+        if !created {
+            if let Some(origin) = origin {
+                srv.send_to_connection(origin, &ack);
+            }
+            return Ok(message);
+        }
+
+        let event = ServerEvent::MessageCreated {
+            conversation_id: message.conversation_id,
+            sender_id,
+            message: message.clone(),
+        };
+        srv.send_message(to_user_id, &event);
+
+        if let Some(origin) = origin {
+            srv.send_to_connection(origin, &ack);
+        }
+
+        Ok(message)
+    }
+
+    /// Send a group message: save it and push it to the other members' devices.
+    /// `origin` and retries work as in `send_direct`.
+    pub async fn send_group(
+        pool: &DbPool,
+        srv: &ChatServer,
+        sender_id: i32,
+        group_id: i32,
+        content: &str,
+        client_msg_id: Option<String>,
+        origin: Option<&WsClient>,
+    ) -> Result<Message, Box<dyn std::error::Error>> {
+        Self::check_content(content, srv.max_content_length())?;
+        Self::check_client_msg_id(client_msg_id.as_deref())?;
+
+        log::info!("Group Message from {} to group {}: {}", sender_id, group_id, content);
+
+        let (message, created) = MessageRepository::create_group_message(
+            pool, sender_id, group_id, content, client_msg_id.as_deref(),
+        ).await?;
+        let ack = ServerEvent::Ack {
+            client_msg_id,
+            message_id: message.id,
+            sent_at: message.sent_at,
+        };
+
+        if created {
+            // The sender gets the Ack instead
+            match MessageRepository::get_group_members(pool, group_id).await {
+                Ok(members) => {
+                    let recipients: Vec<i32> = members.into_iter().filter(|&id| id != sender_id).collect();
+                    let event = ServerEvent::GroupMessageCreated {
+                        group_id,
+                        sender_id,
+                        message: message.clone(),
+                    };
+                    srv.broadcast(&recipients, &event);
+                }
+                Err(e) => log::error!("Failed to fan out group message {}: {}", message.id, e),
+            }
+        }
+
+        if let Some(origin) = origin {
+            srv.send_to_connection(origin, &ack);
+        }
+
+        Ok(message)
+    }
+
+    /// Edit a direct or group message and notify everyone in the conversation
+    pub async fn edit_message(
+        pool: &DbPool,
+        srv: &ChatServer,
+        editor_id: i32,
+        message_id: i32,
+        group_id: Option<i32>,
+        content: &str,
+    ) -> Result<Message, Box<dyn std::error::Error>> {
+        Self::check_content(content, srv.max_content_length())?;
+        let (message, recipients) = match group_id {
+            Some(g_id) => {
+                let message = MessageRepository::edit_group_message(
+                    pool, g_id, message_id, editor_id, content, Self::edit_window(),
+                ).await?;
+                (message, MessageRepository::get_group_members(pool, g_id).await?)
+            }
+            None => {
+                MessageRepository::edit_message(pool, message_id, editor_id, content, Self::edit_window()).await?
+            }
+        };
+
+        // The editor is included so their other devices update too
+        let event = ServerEvent::MessageEdited {
+            message: message.clone(),
+            group_id,
+        };
+        srv.broadcast(&recipients, &event);
+
+        Ok(message)
+    }
+
+    /// Stamp delivery of the given direct messages to `recipient_id` and notify each sender
+    pub async fn confirm_delivery(
+        pool: &DbPool,
+        srv: &ChatServer,
+        recipient_id: i32,
+        message_ids: &[i32],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if message_ids.is_empty() {
+            return Ok(());
+        }
+
+        for receipt in MessageRepository::mark_delivered(pool, recipient_id, message_ids).await? {
+            let event = ServerEvent::MessageDelivered {
+                message_id: receipt.message_id,
+                conversation_id: receipt.conversation_id,
+                delivered_at: receipt.delivered_at,
+            };
+            srv.send_message(receipt.sender_id, &event);
+        }
+
+        Ok(())
+    }
+
+    /// Where a connection's writer reports the direct messages it has sent to `user_id`.
+    /// They are confirmed delivered in batches, until every sender is dropped.
+    pub fn delivery_confirmations(pool: &DbPool, srv: &ChatServer, user_id: i32) -> mpsc::UnboundedSender<i32> {
+        let (sender, mut written) = mpsc::unbounded_channel();
+        let pool = pool.clone();
+        let srv = srv.clone();
+
+        actix_rt::spawn(async move {
+            while let Some(message_id) = written.recv().await {
+                // A replay writes many at once; confirm them together
+                let mut message_ids = vec![message_id];
+                while let Ok(message_id) = written.try_recv() {
+                    message_ids.push(message_id);
+                }
+
+                if let Err(e) = Self::confirm_delivery(&pool, &srv, user_id, &message_ids).await {
+                    log::error!("Failed to mark messages {:?} delivered: {}", message_ids, e);
+                }
+            }
+        });
+
+        sender
+    }
+
+    /// Create a group with its initial members
+    pub async fn create_group(
+        pool: &DbPool,
+        srv: &ChatServer,
+        creator_id: i32,
+        name: &str,
+        description: Option<String>,
+        member_ids: Vec<i32>,
+    ) -> Result<Group, Box<dyn std::error::Error>> {
+        let group = MessageRepository::create_group(pool, creator_id, name, description, member_ids).await?;
+        // Typing may have cached this id as memberless before the group existed
+        srv.typing().forget_participants(TypingTarget::Group(group.id));
+        Ok(group)
+    }
+
+    /// Relay a typing indicator to the other participant or the other group members.
+    /// `origin` is the connection it came from; closing it stops the indicator
+    /// once no other connection is still refreshing it.
+    pub async fn typing(
+        pool: &DbPool,
+        srv: &ChatServer,
+        user_id: i32,
+        conversation_id: Option<i32>,
+        group_id: Option<i32>,
+        is_typing: bool,
+        origin: Option<&WsClient>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let target = match (group_id, conversation_id) {
+            (Some(g_id), _) => TypingTarget::Group(g_id),
+            (None, Some(c_id)) => TypingTarget::Conversation(c_id),
+            (None, None) => return Err("conversation_id or group_id is required".into()),
+        };
+
+        // Typing arrives on every keystroke burst, so participants are cached
+        let participants = match srv.typing().participants(target) {
+            Some(participants) => participants,
+            None => {
+                let participants = match target {
+                    TypingTarget::Group(g_id) => MessageRepository::get_group_members(pool, g_id).await?,
+                    TypingTarget::Conversation(c_id) => {
+                        match MessageRepository::get_conversation_partner(pool, c_id, user_id).await? {
+                            Some(partner_id) => vec![user_id, partner_id],
+                            None => return Err("Conversation not found".into()),
+                        }
+                    }
+                };
+                srv.typing().remember_participants(target, participants.clone());
+                participants
+            }
+        };
+
+        if !participants.contains(&user_id) {
+            return Err(match target {
+                TypingTarget::Group(_) => "You are not a member of this group".into(),
+                TypingTarget::Conversation(_) => "Conversation not found".into(),
+            });
+        }
+
+        let recipients: Vec<i32> = participants.into_iter().filter(|&id| id != user_id).collect();
+        srv.typing().update(srv, user_id, target, recipients, is_typing, origin);
+
+        Ok(())
+    }
+
+    /// Mark a direct message read and notify its sender and the reader's other devices
+    pub async fn mark_message_read(
+        pool: &DbPool,
+        srv: &ChatServer,
+        reader_id: i32,
+        message_id: i32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(receipt) = MessageRepository::mark_message_read(pool, message_id, reader_id).await? {
+            let event = ServerEvent::MessageRead { message_id, reader_id };
+            srv.broadcast(&[receipt.sender_id, reader_id], &event);
+        }
+
+        Ok(())
+    }
+
+    /// Mark a conversation read up to a message and notify both participants
+    pub async fn mark_conversation_read(
+        pool: &DbPool,
+        srv: &ChatServer,
+        reader_id: i32,
+        conversation_id: i32,
+        up_to_message_id: i32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let receipt = match MessageRepository::mark_conversation_read(
+            pool, conversation_id, reader_id, up_to_message_id,
+        ).await? {
+            Some(receipt) => receipt,
+            None => return Ok(()), // Nothing unread
+        };
+
+        let event = ServerEvent::ConversationRead {
+            conversation_id,
+            reader_id,
+            up_to_message_id,
+            read_at: receipt.read_at,
+        };
+        srv.broadcast(&[receipt.sender_id, reader_id], &event);
+
+        Ok(())
+    }
+
+    /// Advance the caller's read watermark in a group and tell the other members
+    pub async fn mark_group_read(
+        pool: &DbPool,
+        srv: &ChatServer,
+        user_id: i32,
+        group_id: i32,
+        up_to_message_id: i32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let read_at = match MessageRepository::mark_group_read(pool, group_id, user_id, up_to_message_id).await? {
+            Some(read_at) => read_at,
+            None => return Ok(()), // Already read that far
+        };
+
+        // The reader is included so their other devices clear the unread badge
+        let members = MessageRepository::get_group_members(pool, group_id).await?;
+        let event = ServerEvent::GroupRead {
+            group_id,
+            reader_id: user_id,
+            up_to_message_id,
+            read_at,
+        };
+        srv.broadcast(&members, &event);
+
+        Ok(())
+    }
+
+    /// Delete a message for everyone (sender only) or hide it for the caller
+    pub async fn delete_message(
+        pool: &DbPool,
+        srv: &ChatServer,
+        user_id: i32,
+        message_id: i32,
+        group_id: Option<i32>,
+        for_everyone: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (conversation_id, recipients) = match (group_id, for_everyone) {
+            (Some(g_id), true) => {
+                MessageRepository::delete_group_message_for_everyone(pool, g_id, message_id, user_id).await?;
+                (None, MessageRepository::get_group_members(pool, g_id).await?)
+            }
+            (None, true) => {
+                let (c_id, participants) =
+                    MessageRepository::delete_message_for_everyone(pool, message_id, user_id).await?;
+                (Some(c_id), participants)
+            }
+            // Delete-for-me only concerns the caller's own devices
+            (Some(g_id), false) => {
+                MessageRepository::hide_group_message(pool, g_id, message_id, user_id).await?;
+                (None, vec![user_id])
+            }
+            (None, false) => {
+                let c_id = MessageRepository::hide_message(pool, message_id, user_id).await?;
+                (Some(c_id), vec![user_id])
+            }
+        };
+
+        let event = ServerEvent::MessageDeleted {
+            message_id,
+            conversation_id,
+            group_id,
+            for_everyone,
+        };
+        srv.broadcast(&recipients, &event);
+
+        Ok(())
+    }
+}
@@ -3,6 +3,8 @@ pub mod users;
 pub mod ws;
 pub mod chat;
 pub mod contacts;
+pub mod sync;
+pub mod presence;
 
 // Export module configurations
 pub use auth::configure as configure_auth;
@@ -10,3 +12,6 @@ pub use users::configure as configure_users;
 pub use ws::configure as configure_ws;
 pub use contacts::configure as configure_contacts;
 pub use chat::configure as configure_chats;
+pub use sync::configure as configure_sync;
+pub use presence::configure as configure_presence;
+pub use ws::sse::configure as configure_events;
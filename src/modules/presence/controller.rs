@@ -0,0 +1,29 @@
+use actix_web::{web, HttpResponse, HttpRequest, HttpMessage};
+use crate::db::DbPool;
+use crate::common::{ApiResponse, ErrorResponse};
+use crate::modules::presence::services::PresenceService;
+
+/// GET /api/presence
+/// Status and last_seen of every accepted contact
+pub async fn get_contacts_presence(
+    pool: web::Data<DbPool>,
+    presence: web::Data<PresenceService>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let user_id = match req.extensions().get::<i32>().copied() {
+        Some(id) => id,
+        None => return ErrorResponse::unauthorized("Unauthorized"),
+    };
+
+    match presence.contacts_presence(&pool, user_id).await {
+        Ok(list) => ApiResponse::success("Presence retrieved", list),
+        Err(e) => {
+            log::error!("Get presence error: {}", e);
+            ErrorResponse::internal_error("Failed to retrieve presence")
+        }
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/presence", web::get().to(get_contacts_presence));
+}
@@ -0,0 +1,7 @@
+pub mod model;
+pub mod repository;
+pub mod services;
+pub mod controller;
+
+pub use services::PresenceService;
+pub use controller::configure;
@@ -0,0 +1,41 @@
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+
+/// What a user's contacts see. `Online` is the default while any device is connected;
+/// `Away` and `Dnd` are set by the client and last until the user's final device disconnects.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceStatus {
+    Online,
+    Away,
+    Dnd,
+    Offline,
+}
+
+impl PresenceStatus {
+    /// Value stored in `users.presence_status`; `Online` and `Offline` are stored as NULL
+    pub fn stored(&self) -> Option<&'static str> {
+        match self {
+            PresenceStatus::Away => Some("away"),
+            PresenceStatus::Dnd => Some("dnd"),
+            PresenceStatus::Online | PresenceStatus::Offline => None,
+        }
+    }
+
+    /// Status of a connected user from `users.presence_status`
+    pub fn from_stored(value: Option<&str>) -> Self {
+        match value {
+            Some("away") => PresenceStatus::Away,
+            Some("dnd") => PresenceStatus::Dnd,
+            _ => PresenceStatus::Online,
+        }
+    }
+}
+
+/// Presence of one user, as returned by `GET /api/presence`
+#[derive(Debug, Serialize, Clone)]
+pub struct UserPresence {
+    pub user_id: i32,
+    pub status: PresenceStatus,
+    pub last_seen: Option<DateTime<Utc>>,
+}
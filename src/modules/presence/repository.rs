@@ -0,0 +1,233 @@
+use chrono::{DateTime, Utc};
+use deadpool_postgres::GenericClient;
+use crate::db::DbPool;
+use crate::modules::presence::model::PresenceStatus;
+
+/// Advisory lock class for a user's presence rows; the user id is the second key.
+/// Connects and disconnects of one user are serialized so exactly one of them
+/// sees the first device arrive or the last one leave.
+const PRESENCE_LOCK: i32 = 0x7072;
+
+/// A connection whose node hasn't refreshed it for this long no longer counts
+const LIVE: &str = "seen_at > NOW() - INTERVAL '90 seconds'";
+
+pub struct PresenceRepository;
+
+impl PresenceRepository {
+    /// Stamp `users.last_seen`, clear the chosen status, and return `last_seen`
+    pub async fn touch_last_seen(
+        pool: &DbPool,
+        user_id: i32,
+    ) -> Result<DateTime<Utc>, Box<dyn std::error::Error>> {
+        let client = pool.get().await?;
+
+        let row = client.query_one(
+            "UPDATE users SET last_seen = CURRENT_TIMESTAMP, presence_status = NULL
+             WHERE id = $1 RETURNING last_seen",
+            &[&user_id]
+        ).await?;
+
+        Ok(row.get(0))
+    }
+
+    /// Same as `touch_last_seen` for several users at once, returning each one's `last_seen`
+    pub async fn touch_last_seen_many(
+        pool: &DbPool,
+        user_ids: &[i32],
+    ) -> Result<Vec<(i32, DateTime<Utc>)>, Box<dyn std::error::Error>> {
+        let client = pool.get().await?;
+
+        let rows = client.query(
+            "UPDATE users SET last_seen = CURRENT_TIMESTAMP, presence_status = NULL
+             WHERE id = ANY($1) RETURNING id, last_seen",
+            &[&user_ids]
+        ).await?;
+
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    /// Get `last_seen` for several users
+    pub async fn get_last_seen(
+        pool: &DbPool,
+        user_ids: &[i32],
+    ) -> Result<Vec<(i32, Option<DateTime<Utc>>)>, Box<dyn std::error::Error>> {
+        let client = pool.get().await?;
+
+        let rows = client.query(
+            "SELECT id, last_seen FROM users WHERE id = ANY($1)",
+            &[&user_ids]
+        ).await?;
+
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    /// Store the status a connected user chose and return the one it replaced
+    pub async fn set_status(
+        pool: &DbPool,
+        user_id: i32,
+        status: PresenceStatus,
+    ) -> Result<PresenceStatus, Box<dyn std::error::Error>> {
+        let client = pool.get().await?;
+
+        // The subquery reads the row as it was before this update
+        let row = client.query_one(
+            "UPDATE users u SET presence_status = $2
+             FROM (SELECT presence_status FROM users WHERE id = $1 FOR UPDATE) previous
+             WHERE u.id = $1
+             RETURNING previous.presence_status",
+            &[&user_id, &status.stored()]
+        ).await?;
+
+        Ok(PresenceStatus::from_stored(row.get(0)))
+    }
+
+    /// Chosen status of each of these users, as it applies while they are connected
+    pub async fn get_statuses(
+        pool: &DbPool,
+        user_ids: &[i32],
+    ) -> Result<Vec<(i32, PresenceStatus)>, Box<dyn std::error::Error>> {
+        let client = pool.get().await?;
+
+        let rows = client.query(
+            "SELECT id, presence_status FROM users WHERE id = ANY($1)",
+            &[&user_ids]
+        ).await?;
+
+        Ok(rows.iter().map(|row| (row.get(0), PresenceStatus::from_stored(row.get(1)))).collect())
+    }
+
+    /// Record an attached connection and return how many the user now has on all nodes
+    pub async fn add_connection(
+        pool: &DbPool,
+        node_id: &str,
+        connection_id: &str,
+        user_id: i32,
+    ) -> Result<i64, Box<dyn std::error::Error>> {
+        let mut client = pool.get().await?;
+        let transaction = client.transaction().await?;
+
+        transaction.execute("SELECT pg_advisory_xact_lock($1, $2)", &[&PRESENCE_LOCK, &user_id]).await?;
+        transaction.execute(
+            "INSERT INTO presence_connections (connection_id, node_id, user_id) VALUES ($1, $2, $3)
+             ON CONFLICT (connection_id) DO UPDATE SET node_id = $2, seen_at = CURRENT_TIMESTAMP",
+            &[&connection_id, &node_id, &user_id]
+        ).await?;
+        let count = Self::count_live(&transaction, user_id).await?;
+
+        transaction.commit().await?;
+        Ok(count)
+    }
+
+    /// Forget a connection and return how many the user still has on all nodes
+    pub async fn remove_connection(
+        pool: &DbPool,
+        connection_id: &str,
+        user_id: i32,
+    ) -> Result<i64, Box<dyn std::error::Error>> {
+        let mut client = pool.get().await?;
+        let transaction = client.transaction().await?;
+
+        transaction.execute("SELECT pg_advisory_xact_lock($1, $2)", &[&PRESENCE_LOCK, &user_id]).await?;
+        transaction.execute(
+            "DELETE FROM presence_connections WHERE connection_id = $1",
+            &[&connection_id]
+        ).await?;
+        let count = Self::count_live(&transaction, user_id).await?;
+
+        transaction.commit().await?;
+        Ok(count)
+    }
+
+    /// Forget every connection of a node; returns the users left with none on any node
+    pub async fn remove_node(
+        pool: &DbPool,
+        node_id: &str,
+    ) -> Result<Vec<i32>, Box<dyn std::error::Error>> {
+        let mut client = pool.get().await?;
+        let transaction = client.transaction().await?;
+
+        // Locked in id order so two nodes shutting down at once can't deadlock
+        transaction.execute(
+            "SELECT pg_advisory_xact_lock($1, user_id)
+             FROM (SELECT DISTINCT user_id FROM presence_connections WHERE node_id = $2 ORDER BY user_id) users",
+            &[&PRESENCE_LOCK, &node_id]
+        ).await?;
+        let rows = transaction.query(
+            &format!(
+                "WITH removed AS (
+                    DELETE FROM presence_connections WHERE node_id = $1 RETURNING user_id
+                 )
+                 SELECT DISTINCT user_id FROM removed
+                 WHERE NOT EXISTS (
+                    SELECT 1 FROM presence_connections pc
+                    WHERE pc.user_id = removed.user_id AND pc.node_id <> $1 AND pc.{}
+                 )",
+                LIVE
+            ),
+            &[&node_id]
+        ).await?;
+
+        transaction.commit().await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    /// Keep a node's connections live, and drop the ones of nodes that stopped
+    /// refreshing theirs (crashed). Returns the users that left with them.
+    pub async fn refresh_node(
+        pool: &DbPool,
+        node_id: &str,
+    ) -> Result<Vec<i32>, Box<dyn std::error::Error>> {
+        let client = pool.get().await?;
+
+        client.execute(
+            "UPDATE presence_connections SET seen_at = CURRENT_TIMESTAMP WHERE node_id = $1",
+            &[&node_id]
+        ).await?;
+
+        let rows = client.query(
+            &format!(
+                "WITH expired AS (
+                    DELETE FROM presence_connections WHERE NOT ({}) RETURNING user_id
+                 )
+                 SELECT DISTINCT user_id FROM expired
+                 WHERE NOT EXISTS (
+                    SELECT 1 FROM presence_connections pc WHERE pc.user_id = expired.user_id AND pc.{}
+                 )",
+                LIVE, LIVE
+            ),
+            &[]
+        ).await?;
+
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    /// Which of these users have a live connection on any node
+    pub async fn online_users(
+        pool: &DbPool,
+        user_ids: &[i32],
+    ) -> Result<Vec<i32>, Box<dyn std::error::Error>> {
+        let client = pool.get().await?;
+
+        let rows = client.query(
+            &format!(
+                "SELECT DISTINCT user_id FROM presence_connections WHERE user_id = ANY($1) AND {}",
+                LIVE
+            ),
+            &[&user_ids]
+        ).await?;
+
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    async fn count_live<C: GenericClient>(
+        client: &C,
+        user_id: i32,
+    ) -> Result<i64, Box<dyn std::error::Error>> {
+        let row = client.query_one(
+            &format!("SELECT COUNT(*) FROM presence_connections WHERE user_id = $1 AND {}", LIVE),
+            &[&user_id]
+        ).await?;
+
+        Ok(row.get(0))
+    }
+}
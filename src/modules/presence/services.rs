@@ -0,0 +1,223 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use crate::db::DbPool;
+use crate::modules::contacts::repository::ContactRepository;
+use crate::modules::presence::model::{PresenceStatus, UserPresence};
+use crate::modules::presence::repository::PresenceRepository;
+use crate::modules::ws::type_def::{ServerEvent, WsClient};
+use crate::modules::ws::ChatServer;
+
+/// How often a node refreshes its rows in presence_connections
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Tracks each user's presence across their devices on every node. Connections are
+/// recorded in presence_connections, so a user only goes offline once their last
+/// device on any node is gone. Away / dnd are kept in `users.presence_status` so
+/// every node reports the same status.
+#[derive(Clone)]
+pub struct PresenceService {
+    /// Identifies this node's rows in presence_connections
+    node_id: String,
+}
+
+impl PresenceService {
+    pub fn new() -> Self {
+        Self {
+            node_id: uuid::Uuid::new_v4().to_string(),
+        }
+    }
+
+    /// Call after `ChatServer::join`; announces the user when their first device
+    /// on any node connects
+    pub async fn connected(&self, pool: &DbPool, srv: &ChatServer, client: &WsClient) {
+        let devices = match PresenceRepository::add_connection(pool, &self.node_id, &client.connection_id, client.user_id).await {
+            Ok(devices) => devices,
+            Err(e) => {
+                log::error!("Failed to record connection of {}: {}", client.user_id, e);
+                return;
+            }
+        };
+        if devices != 1 {
+            return;
+        }
+
+        // A status left behind by nodes that all went down doesn't carry over
+        if let Err(e) = PresenceRepository::set_status(pool, client.user_id, PresenceStatus::Online).await {
+            log::error!("Failed to reset status of {}: {}", client.user_id, e);
+        }
+        self.announce(pool, srv, client.user_id, PresenceStatus::Online, None).await;
+    }
+
+    /// Call after `ChatServer::leave` or `detach`; once the user's last device on any
+    /// node is gone, stamps `last_seen` and announces the user as offline
+    pub async fn disconnected(&self, pool: &DbPool, srv: &ChatServer, client: &WsClient) {
+        // Resumed by another transport, or `shutdown` is taking everyone offline
+        if srv.is_attached(client) || srv.is_shutting_down() {
+            return;
+        }
+
+        let devices = match PresenceRepository::remove_connection(pool, &client.connection_id, client.user_id).await {
+            Ok(devices) => devices,
+            Err(e) => {
+                log::error!("Failed to remove connection of {}: {}", client.user_id, e);
+                return;
+            }
+        };
+        if devices > 0 {
+            return;
+        }
+
+        self.went_offline(pool, srv, client.user_id).await;
+    }
+
+    /// Take this node's connections out of presence before it shuts down, announcing
+    /// the users who aren't connected to another node as offline. Gives up after `grace`.
+    /// Call before `ChatServer::shutdown` so the announcements are flushed.
+    pub async fn shutdown(&self, pool: &DbPool, srv: &ChatServer, grace: Duration) {
+        if tokio::time::timeout(grace, self.take_node_offline(pool, srv)).await.is_err() {
+            log::warn!("Presence was not updated within {:?} of shutting down", grace);
+        }
+    }
+
+    async fn take_node_offline(&self, pool: &DbPool, srv: &ChatServer) {
+        let offline = match PresenceRepository::remove_node(pool, &self.node_id).await {
+            Ok(users) => users,
+            Err(e) => {
+                log::error!("Failed to remove this node's connections from presence: {}", e);
+                return;
+            }
+        };
+        if offline.is_empty() {
+            return;
+        }
+
+        // One update for everyone, then the announcements side by side
+        let last_seen: HashMap<i32, chrono::DateTime<chrono::Utc>> =
+            match PresenceRepository::touch_last_seen_many(pool, &offline).await {
+                Ok(stamped) => stamped.into_iter().collect(),
+                Err(e) => {
+                    log::error!("Failed to update last_seen on shutdown: {}", e);
+                    HashMap::new()
+                }
+            };
+        futures_util::future::join_all(offline.iter().map(|&user_id| {
+            self.announce(pool, srv, user_id, PresenceStatus::Offline, last_seen.get(&user_id).copied())
+        }))
+        .await;
+    }
+
+    /// Refresh this node's connections every `HEARTBEAT_INTERVAL`, and announce the
+    /// users of nodes that stopped refreshing theirs as offline. Runs until the process exits.
+    pub async fn run_heartbeat(self, pool: DbPool, srv: ChatServer) {
+        let mut ticks = tokio::time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            ticks.tick().await;
+            if srv.is_shutting_down() {
+                return;
+            }
+
+            match PresenceRepository::refresh_node(&pool, &self.node_id).await {
+                Ok(offline) => {
+                    for user_id in offline {
+                        self.went_offline(&pool, &srv, user_id).await;
+                    }
+                }
+                Err(e) => log::error!("Failed to refresh presence connections: {}", e),
+            }
+        }
+    }
+
+    /// Stamp `last_seen` and tell everyone the user is offline
+    async fn went_offline(&self, pool: &DbPool, srv: &ChatServer, user_id: i32) {
+        let last_seen = match PresenceRepository::touch_last_seen(pool, user_id).await {
+            Ok(last_seen) => Some(last_seen),
+            Err(e) => {
+                log::error!("Failed to update last_seen for {}: {}", user_id, e);
+                None
+            }
+        };
+        self.announce(pool, srv, user_id, PresenceStatus::Offline, last_seen).await;
+    }
+
+    /// Set a connected user's status (online, away or dnd)
+    pub async fn set_status(
+        &self,
+        pool: &DbPool,
+        srv: &ChatServer,
+        user_id: i32,
+        status: PresenceStatus,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if status == PresenceStatus::Offline {
+            return Err("Status must be online, away or dnd".into());
+        }
+
+        let previous = PresenceRepository::set_status(pool, user_id, status).await?;
+        if previous != status {
+            self.announce(pool, srv, user_id, status, None).await;
+        }
+
+        Ok(())
+    }
+
+    /// Presence of each of the user's accepted contacts
+    pub async fn contacts_presence(
+        &self,
+        pool: &DbPool,
+        user_id: i32,
+    ) -> Result<Vec<UserPresence>, Box<dyn std::error::Error>> {
+        let contact_ids: Vec<i32> = ContactRepository::get_contacts(pool, user_id)
+            .await?
+            .into_iter()
+            .map(|contact| contact.contact_id)
+            .collect();
+
+        let last_seen = PresenceRepository::get_last_seen(pool, &contact_ids).await?;
+        let chosen: HashMap<i32, PresenceStatus> = PresenceRepository::get_statuses(pool, &contact_ids)
+            .await?
+            .into_iter()
+            .collect();
+        // Contacts connected to other nodes count too
+        let online: HashSet<i32> = PresenceRepository::online_users(pool, &contact_ids)
+            .await?
+            .into_iter()
+            .collect();
+
+        Ok(last_seen
+            .into_iter()
+            .map(|(id, last_seen)| UserPresence {
+                user_id: id,
+                status: match chosen.get(&id) {
+                    Some(&status) if online.contains(&id) => status,
+                    _ => PresenceStatus::Offline,
+                },
+                last_seen,
+            })
+            .collect())
+    }
+
+    /// Send a status change to the user's accepted contacts and their own devices
+    async fn announce(
+        &self,
+        pool: &DbPool,
+        srv: &ChatServer,
+        user_id: i32,
+        status: PresenceStatus,
+        last_seen: Option<chrono::DateTime<chrono::Utc>>,
+    ) {
+        let mut recipients = match ContactRepository::get_contacts(pool, user_id).await {
+            Ok(contacts) => contacts.into_iter().map(|contact| contact.contact_id).collect::<Vec<i32>>(),
+            Err(e) => {
+                log::error!("Failed to load contacts for presence of {}: {}", user_id, e);
+                return;
+            }
+        };
+        recipients.push(user_id);
+
+        let event = ServerEvent::UserStatus {
+            user_id,
+            status,
+            last_seen,
+        };
+        srv.broadcast(&recipients, &event);
+    }
+}
@@ -0,0 +1,46 @@
+use actix_web::{web, HttpResponse, HttpRequest, HttpMessage};
+use crate::db::DbPool;
+use crate::common::{ApiResponse, ErrorResponse};
+use crate::modules::chat::ChatService;
+use crate::modules::sync::repository::SyncRepository;
+use crate::modules::ws::ChatServer;
+
+/// GET /api/sync?since=0&limit=200
+#[derive(serde::Deserialize)]
+pub struct SyncQuery {
+    pub since: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+pub async fn sync(
+    pool: web::Data<DbPool>,
+    srv: web::Data<ChatServer>,
+    req: HttpRequest,
+    query: web::Query<SyncQuery>,
+) -> HttpResponse {
+    let user_id = match req.extensions().get::<i32>().copied() {
+        Some(id) => id,
+        None => return ErrorResponse::unauthorized("Unauthorized"),
+    };
+
+    let since = query.since.unwrap_or(0).max(0);
+    let limit = query.limit.unwrap_or(200).clamp(1, 500);
+
+    match SyncRepository::get_events(&pool, user_id, since, limit).await {
+        Ok(batch) => {
+            // Direct messages in this page have now reached a device
+            if let Err(e) = ChatService::confirm_delivery(&pool, &srv, user_id, &batch.direct_message_ids()).await {
+                log::error!("Failed to mark synced messages delivered for {}: {}", user_id, e);
+            }
+            ApiResponse::success("Changes retrieved", batch)
+        }
+        Err(e) => {
+            log::error!("Sync error: {}", e);
+            ErrorResponse::internal_error("Failed to sync")
+        }
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/sync", web::get().to(sync));
+}
@@ -0,0 +1,8 @@
+pub mod model;
+pub mod repository;
+pub mod services;
+pub mod controller;
+
+pub use repository::SyncRepository;
+pub use services::SyncService;
+pub use controller::configure;
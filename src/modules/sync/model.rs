@@ -0,0 +1,64 @@
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+
+/// Kinds of changes recorded in a user's sync feed
+#[derive(Debug, Clone, Copy)]
+pub enum SyncEventKind {
+    MessageCreated,
+    GroupMessageCreated,
+    MessageDelivered,
+    MessageRead,
+    ConversationRead,
+    GroupRead,
+    MessageEdited,
+    MessageDeleted,
+    GroupCreated,
+}
+
+impl SyncEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SyncEventKind::MessageCreated => "message_created",
+            SyncEventKind::GroupMessageCreated => "group_message_created",
+            SyncEventKind::MessageDelivered => "message_delivered",
+            SyncEventKind::MessageRead => "message_read",
+            SyncEventKind::ConversationRead => "conversation_read",
+            SyncEventKind::GroupRead => "group_read",
+            SyncEventKind::MessageEdited => "message_edited",
+            SyncEventKind::MessageDeleted => "message_deleted",
+            SyncEventKind::GroupCreated => "group_created",
+        }
+    }
+}
+
+/// A single change in a user's feed
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SyncEvent {
+    pub cursor: i64,
+    pub kind: String,
+    pub payload: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A page of changes. Pass `cursor` back as `since` to continue.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SyncBatch {
+    pub events: Vec<SyncEvent>,
+    pub cursor: i64,
+    pub has_more: bool,
+    /// The requested cursor is older than the retained feed: reload state over REST,
+    /// then continue from `cursor`
+    pub resync_required: bool,
+}
+
+impl SyncBatch {
+    /// Ids of the direct messages created in this page
+    pub fn direct_message_ids(&self) -> Vec<i32> {
+        self.events
+            .iter()
+            .filter(|event| event.kind == SyncEventKind::MessageCreated.as_str())
+            .filter_map(|event| event.payload.get("message")?.get("id")?.as_i64())
+            .filter_map(|id| i32::try_from(id).ok())
+            .collect()
+    }
+}
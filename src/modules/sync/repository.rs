@@ -0,0 +1,135 @@
+use deadpool_postgres::GenericClient;
+use crate::db::DbPool;
+use crate::modules::sync::model::{SyncBatch, SyncEvent, SyncEventKind};
+
+/// Takes the next seq of every user in `$1` (an INT[]) as `seqs (user_id, last_seq)`.
+/// Each counter row stays locked until the transaction ends, so events of one user
+/// commit in seq order; users are locked in id order so two writers can't deadlock.
+const NEXT_SEQS: &str = "WITH seqs AS (
+    INSERT INTO sync_sequences (user_id, last_seq)
+    SELECT DISTINCT u, 1 FROM unnest($1::INT[]) AS u ORDER BY u
+    ON CONFLICT (user_id) DO UPDATE SET last_seq = sync_sequences.last_seq + 1
+    RETURNING user_id, last_seq
+)";
+
+pub struct SyncRepository;
+
+impl SyncRepository {
+    /// Append an event to the feed of every given user.
+    /// Takes a generic client so it can run inside the caller's transaction.
+    pub async fn record<C: GenericClient>(
+        client: &C,
+        user_ids: &[i32],
+        kind: SyncEventKind,
+        payload: &serde_json::Value,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        client.execute(
+            &format!(
+                "{} INSERT INTO sync_events (user_id, seq, kind, payload)
+                 SELECT user_id, last_seq, $2::VARCHAR, $3::JSONB FROM seqs",
+                NEXT_SEQS
+            ),
+            &[&user_ids, &kind.as_str(), payload]
+        ).await?;
+
+        Ok(())
+    }
+
+    /// Append an event to the feed of every member of a group
+    pub async fn record_for_group<C: GenericClient>(
+        client: &C,
+        group_id: i32,
+        kind: SyncEventKind,
+        payload: &serde_json::Value,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let rows = client.query(
+            "SELECT user_id FROM group_members WHERE group_id = $1",
+            &[&group_id]
+        ).await?;
+        let members: Vec<i32> = rows.iter().map(|row| row.get(0)).collect();
+
+        Self::record(client, &members, kind, payload).await
+    }
+
+    /// Blank out a deleted message's content in every feed event that carried it
+    pub async fn scrub_message<C: GenericClient>(
+        client: &C,
+        message_id: i32,
+        group_id: Option<i32>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // DM events have no group_id key; group events carry the group they belong to.
+        // The id lookup matches idx_sync_events_message_id.
+        client.execute(
+            "UPDATE sync_events
+             SET payload = jsonb_set(payload, '{message,content}', '\"\"'::jsonb)
+             WHERE payload ? 'message'
+               AND (payload->'message'->>'id')::INT = $1
+               AND (payload->>'group_id')::INT IS NOT DISTINCT FROM $2",
+            &[&message_id, &group_id]
+        ).await?;
+
+        Ok(())
+    }
+
+    /// Get a user's events after the given cursor, oldest first
+    pub async fn get_events(
+        pool: &DbPool,
+        user_id: i32,
+        since: i64,
+        limit: i64,
+    ) -> Result<SyncBatch, Box<dyn std::error::Error>> {
+        let client = pool.get().await?;
+
+        // Seqs have no gaps, so a cursor below the oldest retained event (or, once every
+        // event was pruned, below the latest seq) means events the client missed are gone
+        let row = client.query_one(
+            "SELECT
+                 (SELECT MIN(seq) FROM sync_events WHERE user_id = $1),
+                 COALESCE((SELECT last_seq FROM sync_sequences WHERE user_id = $1), 0)",
+            &[&user_id]
+        ).await?;
+        let oldest: Option<i64> = row.get(0);
+        let last_seq: i64 = row.get(1);
+        if since < oldest.unwrap_or(last_seq + 1) - 1 {
+            return Ok(SyncBatch { events: Vec::new(), cursor: last_seq, has_more: false, resync_required: true });
+        }
+
+        // Fetch one extra row to know whether another page exists
+        let rows = client.query(
+            "SELECT seq, kind, payload, created_at
+             FROM sync_events
+             WHERE user_id = $1 AND seq > $2
+             ORDER BY seq ASC
+             LIMIT $3",
+            &[&user_id, &since, &(limit + 1)]
+        ).await?;
+
+        let has_more = rows.len() as i64 > limit;
+        let events: Vec<SyncEvent> = rows.iter().take(limit as usize).map(|row| SyncEvent {
+            cursor: row.get(0),
+            kind: row.get(1),
+            payload: row.get(2),
+            created_at: row.get(3),
+        }).collect();
+
+        let cursor = events.last().map(|e| e.cursor).unwrap_or(since);
+
+        Ok(SyncBatch { events, cursor, has_more, resync_required: false })
+    }
+
+    /// Delete events older than `retention`, returning how many were removed
+    pub async fn prune(
+        pool: &DbPool,
+        retention: chrono::Duration,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        let client = pool.get().await?;
+        let cutoff = chrono::Utc::now() - retention;
+
+        let pruned = client.execute(
+            "DELETE FROM sync_events WHERE created_at < $1",
+            &[&cutoff]
+        ).await?;
+
+        Ok(pruned)
+    }
+}
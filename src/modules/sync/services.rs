@@ -0,0 +1,41 @@
+use std::env;
+use std::time::Duration;
+use crate::db::DbPool;
+use crate::modules::sync::repository::SyncRepository;
+use crate::modules::ws::ChatServer;
+
+/// How often expired events are pruned from sync_events
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Housekeeping for the per-user sync feeds
+pub struct SyncService;
+
+impl SyncService {
+    /// How long events stay in a user's feed (SYNC_RETENTION_DAYS)
+    pub fn retention() -> chrono::Duration {
+        let days = env::var("SYNC_RETENTION_DAYS")
+            .unwrap_or_else(|_| "30".to_string())
+            .parse::<i64>()
+            .unwrap_or(30);
+        chrono::Duration::days(days.max(1))
+    }
+
+    /// Delete events older than the retention window every `PRUNE_INTERVAL`.
+    /// Runs until the server starts shutting down.
+    pub async fn run_pruning(pool: DbPool, srv: ChatServer) {
+        let retention = Self::retention();
+        let mut ticks = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            ticks.tick().await;
+            if srv.is_shutting_down() {
+                return;
+            }
+
+            match SyncRepository::prune(&pool, retention).await {
+                Ok(0) => {}
+                Ok(pruned) => log::info!("Pruned {} expired sync events", pruned),
+                Err(e) => log::error!("Failed to prune sync events: {}", e),
+            }
+        }
+    }
+}
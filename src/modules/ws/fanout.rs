@@ -0,0 +1,223 @@
+use std::env;
+use std::time::Duration;
+use futures_util::{stream, StreamExt as _};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio_postgres::{AsyncMessage, NoTls};
+use crate::db::DbPool;
+use crate::modules::ws::server::ChatServer;
+use crate::modules::ws::type_def::QueuedEvent;
+
+/// NOTIFY channel shared by every node
+const CHANNEL: &str = "chat_fanout";
+
+/// Postgres rejects NOTIFY payloads of 8000 bytes or more; bigger frames go through
+/// the fanout_frames table and only their id is sent
+const MAX_NOTIFY_PAYLOAD: usize = 7900;
+
+/// Delivers frames to users connected to other server nodes.
+/// `ChatServer` always delivers to its own connections first, then publishes here.
+pub trait FanoutBackend: Send + Sync {
+    /// Send an encoded frame to the given users on every other node
+    fn publish(&self, user_ids: &[i32], frame: &str);
+
+    /// Start handing frames published by other nodes to `local`
+    fn subscribe(&self, local: ChatServer);
+}
+
+/// Single node: every connection is local, so there is nothing to forward
+pub struct InMemoryFanout;
+
+impl FanoutBackend for InMemoryFanout {
+    fn publish(&self, _user_ids: &[i32], _frame: &str) {}
+
+    fn subscribe(&self, _local: ChatServer) {}
+}
+
+/// Forwards frames between nodes with Postgres LISTEN/NOTIFY
+pub struct PostgresFanout {
+    node_id: String,
+    pool: DbPool,
+    outgoing: mpsc::UnboundedSender<Envelope>,
+}
+
+/// What travels over the channel. `users` lists the recipients so nodes can tell
+/// from `Route` alone whether a frame concerns them.
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    node: String,
+    users: Vec<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frame: Option<String>,
+    /// Set instead of `frame` when the frame is stored in fanout_frames
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frame_id: Option<i64>,
+}
+
+/// The addressing part of an `Envelope`; reading it skips over the frame without copying it
+#[derive(Deserialize)]
+struct Route {
+    node: String,
+    users: Vec<i32>,
+}
+
+impl PostgresFanout {
+    /// NOTIFY goes through the shared pool; LISTEN needs its own connection,
+    /// opened from DATABASE_URL when `subscribe` is called
+    pub fn new(pool: DbPool) -> Self {
+        let (outgoing, receiver) = mpsc::unbounded_channel();
+
+        // A single publisher keeps frames in the order they were sent
+        actix_rt::spawn(Self::run_publisher(pool.clone(), receiver));
+
+        Self {
+            node_id: uuid::Uuid::new_v4().to_string(),
+            pool,
+            outgoing,
+        }
+    }
+
+    async fn run_publisher(pool: DbPool, mut receiver: mpsc::UnboundedReceiver<Envelope>) {
+        while let Some(envelope) = receiver.recv().await {
+            if let Err(e) = Self::notify(&pool, envelope).await {
+                log::error!("Failed to publish frame to other nodes: {}", e);
+            }
+        }
+    }
+
+    async fn notify(pool: &DbPool, mut envelope: Envelope) -> Result<(), Box<dyn std::error::Error>> {
+        let client = pool.get().await?;
+
+        let mut payload = serde_json::to_string(&envelope)?;
+        if payload.len() > MAX_NOTIFY_PAYLOAD {
+            let frame = envelope.frame.take().unwrap_or_default();
+            let row = client.query_one(
+                "INSERT INTO fanout_frames (payload) VALUES ($1) RETURNING id",
+                &[&frame]
+            ).await?;
+            envelope.frame_id = Some(row.get(0));
+            payload = serde_json::to_string(&envelope)?;
+
+            // Every node has long since read anything this old
+            client.execute(
+                "DELETE FROM fanout_frames WHERE created_at < NOW() - INTERVAL '5 minutes'",
+                &[]
+            ).await?;
+        }
+
+        client.execute("SELECT pg_notify($1, $2)", &[&CHANNEL, &payload]).await?;
+        Ok(())
+    }
+
+    /// Keep a LISTEN connection open, reconnecting if it drops
+    async fn run_listener(node_id: String, pool: DbPool, local: ChatServer) {
+        let database_url = match env::var("DATABASE_URL") {
+            Ok(url) => url,
+            Err(_) => {
+                log::error!("DATABASE_URL must be set for the postgres fanout backend");
+                return;
+            }
+        };
+
+        loop {
+            if let Err(e) = Self::listen(&database_url, &node_id, &pool, &local).await {
+                log::error!("Fanout listener disconnected: {}", e);
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    async fn listen(
+        database_url: &str,
+        node_id: &str,
+        pool: &DbPool,
+        local: &ChatServer,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (client, mut connection) = tokio_postgres::connect(database_url, NoTls).await?;
+
+        // Notifications arrive on the connection, which we have to drive ourselves
+        let (notifications_tx, mut notifications) = mpsc::unbounded_channel();
+        let driver = actix_rt::spawn(async move {
+            let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+            while let Some(message) = messages.next().await {
+                match message {
+                    Ok(AsyncMessage::Notification(n)) => {
+                        if notifications_tx.send(n.payload().to_string()).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        log::error!("Fanout connection error: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        client.batch_execute(&format!("LISTEN {}", CHANNEL)).await?;
+        log::info!("Listening for frames from other nodes (node {})", node_id);
+
+        while let Some(payload) = notifications.recv().await {
+            // Drop our own frames and those for users with no connection here
+            // before decoding (or loading) the frame itself
+            match serde_json::from_str::<Route>(&payload) {
+                Ok(route) if route.node != node_id && local.has_connections(&route.users) => {}
+                Ok(_) => continue,
+                Err(e) => {
+                    log::warn!("Ignoring malformed fanout payload: {}", e);
+                    continue;
+                }
+            }
+            let envelope: Envelope = match serde_json::from_str(&payload) {
+                Ok(envelope) => envelope,
+                Err(e) => {
+                    log::warn!("Ignoring malformed fanout payload: {}", e);
+                    continue;
+                }
+            };
+
+            let frame = match (envelope.frame, envelope.frame_id) {
+                (Some(frame), _) => frame,
+                (None, Some(id)) => match Self::load_frame(pool, id).await {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        log::error!("Failed to load fanout frame {}: {}", id, e);
+                        continue;
+                    }
+                },
+                (None, None) => continue,
+            };
+
+            match QueuedEvent::parse(frame) {
+                Some(frame) => local.deliver_local(&envelope.users, &frame),
+                None => log::warn!("Ignoring malformed fanout frame"),
+            }
+        }
+
+        driver.abort();
+        Err("notification stream ended".into())
+    }
+
+    async fn load_frame(pool: &DbPool, id: i64) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let client = pool.get().await?;
+        let row = client.query_opt("SELECT payload FROM fanout_frames WHERE id = $1", &[&id]).await?;
+        Ok(row.map(|row| row.get(0)))
+    }
+}
+
+impl FanoutBackend for PostgresFanout {
+    fn publish(&self, user_ids: &[i32], frame: &str) {
+        let _ = self.outgoing.send(Envelope {
+            node: self.node_id.clone(),
+            users: user_ids.to_vec(),
+            frame: Some(frame.to_string()),
+            frame_id: None,
+        });
+    }
+
+    fn subscribe(&self, local: ChatServer) {
+        actix_rt::spawn(Self::run_listener(self.node_id.clone(), self.pool.clone(), local));
+    }
+}
@@ -1,6 +1,11 @@
 pub mod type_def;
 pub mod ws;
 pub mod server;
+pub mod outbound;
+pub mod fanout;
+pub mod rate_limit;
+pub mod sse;
+pub mod typing;
 
 pub use ws::configure;
 pub use server::ChatServer;
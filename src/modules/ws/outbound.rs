@@ -0,0 +1,345 @@
+use std::collections::VecDeque;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use actix_ws::{CloseCode, CloseReason, Session};
+use tokio::sync::{mpsc, Notify};
+use crate::modules::ws::type_def::{Codec, QueuedEvent, WireFrame};
+
+/// What to do when a connection's outbound queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Discard the oldest queued frame to make room (the client can catch up with `Sync`)
+    DropOldest,
+    /// Close the connection with a policy violation
+    Disconnect,
+}
+
+/// Outbound queue settings, read from WS_OUTBOUND_CAPACITY, WS_OUTBOUND_POLICY,
+/// WS_REPLAY_BUFFER and WS_RESUME_GRACE
+#[derive(Debug, Clone, Copy)]
+pub struct OutboundConfig {
+    pub capacity: usize,
+    pub policy: OverflowPolicy,
+    /// Recent frames kept so a dropped client can resume where it left off
+    pub replay_capacity: usize,
+    /// How long a dropped connection keeps buffering for a resume
+    pub resume_grace: Duration,
+}
+
+impl OutboundConfig {
+    pub fn from_env() -> Self {
+        let usize_var = |key: &str, default: usize| {
+            env::var(key)
+                .ok()
+                .and_then(|v| v.parse::<usize>().ok())
+                .filter(|&v| v > 0)
+                .unwrap_or(default)
+        };
+
+        let policy = match env::var("WS_OUTBOUND_POLICY").as_deref() {
+            Ok("drop_oldest") => OverflowPolicy::DropOldest,
+            _ => OverflowPolicy::Disconnect,
+        };
+
+        Self {
+            capacity: usize_var("WS_OUTBOUND_CAPACITY", 256),
+            policy,
+            replay_capacity: usize_var("WS_REPLAY_BUFFER", 256),
+            resume_grace: Duration::from_secs(usize_var("WS_RESUME_GRACE", 60) as u64),
+        }
+    }
+}
+
+/// What the consumer of a queue should do next
+pub enum Outgoing {
+    /// Write this frame; frames are numbered from 1 per connection
+    Frame(u64, Arc<QueuedEvent>),
+    /// Stop, sending a close frame with the reason if there is one
+    Close(Option<CloseReason>),
+    /// The client went away or another consumer took over; frames are being kept for a resume
+    Detached,
+}
+
+/// Bounded queue of frames for one connection, drained by the transport
+/// (a WebSocket writer task or an SSE stream). Pushing never waits on the socket,
+/// so a slow client can't stall the sender.
+#[derive(Clone)]
+pub struct OutboundQueue {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    notify: Notify,
+    /// Signalled when the consumer stops
+    drained: Notify,
+    config: OutboundConfig,
+}
+
+struct State {
+    pending: VecDeque<(u64, Arc<QueuedEvent>)>,
+    /// The last `replay_capacity` frames, sent or not
+    replay: VecDeque<(u64, Arc<QueuedEvent>)>,
+    last_seq: u64,
+    /// Id of the current consumer; bumped on every resume so a stale one stops
+    consumer: u64,
+    attached: bool,
+    closed: bool,
+    /// Close frame the consumer sends once the queue is empty, if any
+    close_reason: Option<CloseReason>,
+    finished: bool,
+}
+
+impl OutboundQueue {
+    pub fn new(config: OutboundConfig) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    pending: VecDeque::new(),
+                    replay: VecDeque::new(),
+                    last_seq: 0,
+                    consumer: 0,
+                    attached: true,
+                    closed: false,
+                    close_reason: None,
+                    finished: false,
+                }),
+                notify: Notify::new(),
+                drained: Notify::new(),
+                config,
+            }),
+        }
+    }
+
+    /// Queue a frame. Returns false if no client is attached to receive it
+    /// (closed, dropped for falling behind, or detached and only buffering).
+    pub fn push(&self, frame: Arc<QueuedEvent>) -> bool {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return false;
+        }
+
+        state.last_seq += 1;
+        let seq = state.last_seq;
+        state.replay.push_back((seq, frame.clone()));
+        if state.replay.len() > self.shared.config.replay_capacity {
+            state.replay.pop_front();
+        }
+
+        if !state.attached {
+            return false;
+        }
+
+        if state.pending.len() >= self.shared.config.capacity {
+            match self.shared.config.policy {
+                OverflowPolicy::DropOldest => {
+                    state.pending.pop_front();
+                }
+                OverflowPolicy::Disconnect => {
+                    log::warn!("Disconnecting slow consumer: outbound queue full");
+                    state.closed = true;
+                    state.close_reason = Some(CloseReason {
+                        code: CloseCode::Policy,
+                        description: Some("Outbound queue overflow".to_string()),
+                    });
+                    state.pending.clear();
+                    drop(state);
+                    self.shared.notify.notify_waiters();
+                    return false;
+                }
+            }
+        }
+
+        state.pending.push_back((seq, frame));
+        drop(state);
+        self.shared.notify.notify_waiters();
+        true
+    }
+
+    /// The consumer attached when the queue was created
+    pub fn consumer(&self) -> Consumer {
+        let id = self.shared.state.lock().unwrap().consumer;
+        Consumer { queue: self.clone(), id }
+    }
+
+    /// Stop accepting frames; the consumer flushes what is queued and stops
+    pub fn close(&self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.notify.notify_waiters();
+    }
+
+    /// Like `close`, but the consumer ends by sending a close frame with `reason`
+    pub fn close_with(&self, reason: CloseReason) {
+        let mut state = self.shared.state.lock().unwrap();
+        if !state.closed {
+            state.closed = true;
+            state.close_reason = Some(reason);
+        }
+        drop(state);
+        self.shared.notify.notify_waiters();
+    }
+
+    pub fn is_attached(&self) -> bool {
+        let state = self.shared.state.lock().unwrap();
+        state.attached && !state.closed
+    }
+
+    /// Id of the current consumer
+    pub fn current_consumer(&self) -> u64 {
+        self.shared.state.lock().unwrap().consumer
+    }
+
+    /// Attach a new consumer that has seen every frame up to `last_seq`, replacing the
+    /// previous one even if it hasn't noticed its client is gone yet.
+    /// Fails if the queue is closed or the frames after `last_seq` are no longer buffered.
+    pub fn resume(&self, last_seq: u64) -> Option<Consumer> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed || last_seq > state.last_seq {
+            return None;
+        }
+
+        let oldest = state.replay.front().map_or(state.last_seq + 1, |(seq, _)| *seq);
+        if last_seq + 1 < oldest {
+            return None;
+        }
+
+        state.pending = state
+            .replay
+            .iter()
+            .filter(|(seq, _)| *seq > last_seq)
+            .cloned()
+            .collect();
+        state.consumer += 1;
+        state.attached = true;
+        state.finished = false;
+        let id = state.consumer;
+        drop(state);
+        self.shared.notify.notify_waiters();
+        Some(Consumer { queue: self.clone(), id })
+    }
+
+    /// Wait until the consumer has flushed the queue and stopped. A detached queue
+    /// has no consumer to wait for: its frames stay buffered for a resume instead.
+    pub async fn drained(&self) {
+        loop {
+            let notified = self.shared.drained.notified();
+            {
+                let state = self.shared.state.lock().unwrap();
+                if state.finished || !state.attached {
+                    return;
+                }
+            }
+            notified.await;
+        }
+    }
+}
+
+/// The transport currently draining a queue. Only the latest consumer receives
+/// frames; one replaced by a resume gets `Outgoing::Detached`.
+#[derive(Clone)]
+pub struct Consumer {
+    queue: OutboundQueue,
+    id: u64,
+}
+
+impl Consumer {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn queue(&self) -> &OutboundQueue {
+        &self.queue
+    }
+
+    /// False once a resume has handed the queue to another consumer
+    pub fn is_current(&self) -> bool {
+        self.queue.current_consumer() == self.id
+    }
+
+    /// Wait for the next thing this consumer should do
+    pub async fn recv(&self) -> Outgoing {
+        let shared = &self.queue.shared;
+        loop {
+            let notified = shared.notify.notified();
+            {
+                let mut state = shared.state.lock().unwrap();
+                if state.consumer != self.id || !state.attached {
+                    return Outgoing::Detached;
+                }
+                if let Some((seq, frame)) = state.pending.pop_front() {
+                    return Outgoing::Frame(seq, frame);
+                }
+                if state.closed {
+                    return Outgoing::Close(state.close_reason.take());
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// The client went away: stop consuming but keep recording frames for a resume.
+    /// Returns false if this consumer had already been replaced.
+    pub fn detach(&self) -> bool {
+        let mut state = self.queue.shared.state.lock().unwrap();
+        if state.consumer != self.id {
+            return false;
+        }
+        state.attached = false;
+        state.pending.clear();
+        drop(state);
+        self.queue.shared.notify.notify_waiters();
+        self.queue.shared.drained.notify_waiters();
+        true
+    }
+
+    /// Called when the consumer stops
+    pub fn finish(&self) {
+        let mut state = self.queue.shared.state.lock().unwrap();
+        if state.consumer == self.id {
+            state.finished = true;
+            drop(state);
+            self.queue.shared.drained.notify_waiters();
+        }
+    }
+
+    /// Consume the queue into a WebSocket session, encoding each frame (with its `seq`)
+    /// with the connection's codec. The id of every direct message written goes to
+    /// `delivered`, so delivery is only confirmed for frames that reached the socket.
+    pub async fn write_to(self, mut session: Session, codec: Codec, delivered: mpsc::UnboundedSender<i32>) {
+        loop {
+            match self.recv().await {
+                Outgoing::Frame(seq, frame) => {
+                    let encoded = match codec.encode(&frame, Some(seq)) {
+                        Ok(encoded) => encoded,
+                        Err(e) => {
+                            log::error!("Skipping frame {} that failed to encode as {}: {}", seq, codec.name(), e);
+                            continue;
+                        }
+                    };
+                    if send(&mut session, encoded).await.is_err() {
+                        break;
+                    }
+                    if let Some(message_id) = frame.direct_message_id() {
+                        let _ = delivered.send(message_id);
+                    }
+                }
+                Outgoing::Close(Some(reason)) => {
+                    let _ = session.close(Some(reason)).await;
+                    break;
+                }
+                Outgoing::Close(None) | Outgoing::Detached => break,
+            }
+        }
+
+        self.finish();
+    }
+}
+
+/// Write an encoded frame as a text or binary WebSocket message
+pub async fn send(session: &mut Session, frame: WireFrame) -> Result<(), actix_ws::Closed> {
+    match frame {
+        WireFrame::Text(text) => session.text(text).await,
+        WireFrame::Binary(bytes) => session.binary(bytes).await,
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use crate::modules::ws::type_def::WsMessage;
+
+/// Which budget an inbound frame is charged to
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum RateCategory {
+    /// Sends, edits, deletes, syncs and status changes
+    Message,
+    Typing,
+    /// Read receipts
+    Receipt,
+}
+
+impl RateCategory {
+    pub fn of(msg: &WsMessage) -> Self {
+        match msg {
+            WsMessage::Typing { .. } => RateCategory::Typing,
+            WsMessage::MessageRead { .. }
+            | WsMessage::ConversationRead { .. }
+            | WsMessage::GroupRead { .. } => RateCategory::Receipt,
+            _ => RateCategory::Message,
+        }
+    }
+}
+
+/// Which bucket ran out
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RateScope {
+    Connection,
+    User,
+}
+
+/// Bucket size and refill rate
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    pub burst: f64,
+    pub per_second: f64,
+}
+
+impl Limit {
+    /// Parse "burst:per_second" from an env var, e.g. WS_RATE_MESSAGE=20:5
+    fn from_env(key: &str, default: Limit) -> Limit {
+        env::var(key)
+            .ok()
+            .and_then(|v| {
+                let (burst, rate) = v.split_once(':')?;
+                Some(Limit {
+                    burst: burst.trim().parse().ok()?,
+                    per_second: rate.trim().parse().ok()?,
+                })
+            })
+            .filter(|l| l.burst >= 1.0 && l.per_second > 0.0)
+            .unwrap_or(default)
+    }
+
+    /// A user's devices share twice the budget of a single connection
+    fn per_user(self) -> Limit {
+        Limit {
+            burst: self.burst * 2.0,
+            per_second: self.per_second * 2.0,
+        }
+    }
+}
+
+/// Inbound WebSocket limits, read once from the environment
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitConfig {
+    pub message: Limit,
+    pub typing: Limit,
+    pub receipt: Limit,
+    /// Largest text frame accepted, in bytes (WS_MAX_FRAME_SIZE)
+    pub max_frame_size: usize,
+    /// Largest message content accepted, in characters (WS_MAX_CONTENT_LENGTH)
+    pub max_content_length: usize,
+    /// Violations within `violation_window` before the socket is closed (WS_MAX_VIOLATIONS)
+    pub max_violations: usize,
+    pub violation_window: Duration,
+}
+
+impl RateLimitConfig {
+    pub fn from_env() -> Self {
+        let usize_var = |key: &str, default: usize| {
+            env::var(key)
+                .ok()
+                .and_then(|v| v.parse::<usize>().ok())
+                .filter(|&v| v > 0)
+                .unwrap_or(default)
+        };
+
+        Self {
+            message: Limit::from_env("WS_RATE_MESSAGE", Limit { burst: 20.0, per_second: 5.0 }),
+            typing: Limit::from_env("WS_RATE_TYPING", Limit { burst: 5.0, per_second: 2.0 }),
+            receipt: Limit::from_env("WS_RATE_RECEIPT", Limit { burst: 50.0, per_second: 10.0 }),
+            max_frame_size: usize_var("WS_MAX_FRAME_SIZE", 16 * 1024),
+            max_content_length: usize_var("WS_MAX_CONTENT_LENGTH", 4000),
+            max_violations: usize_var("WS_MAX_VIOLATIONS", 10),
+            violation_window: Duration::from_secs(60),
+        }
+    }
+
+    fn limit(&self, category: RateCategory) -> Limit {
+        match category {
+            RateCategory::Message => self.message,
+            RateCategory::Typing => self.typing,
+            RateCategory::Receipt => self.receipt,
+        }
+    }
+}
+
+/// Classic token bucket
+#[derive(Debug)]
+struct TokenBucket {
+    limit: Limit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: Limit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst);
+        self.updated = now;
+    }
+
+    fn has_token(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= 1.0
+    }
+
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+
+    /// Time until the next token is available
+    fn retry_after(&self) -> Duration {
+        Duration::from_secs_f64(((1.0 - self.tokens) / self.limit.per_second).max(0.0))
+    }
+}
+
+/// Per-user buckets, shared by all of a user's connections on this node
+#[derive(Clone, Default)]
+pub struct UserRateLimiter {
+    buckets: Arc<Mutex<HashMap<(i32, RateCategory), TokenBucket>>>,
+}
+
+impl UserRateLimiter {
+    /// Drop a user's buckets once they have no connections left
+    pub fn forget(&self, user_id: i32) {
+        self.buckets.lock().unwrap().retain(|(id, _), _| *id != user_id);
+    }
+}
+
+/// Result of charging a frame against the limits
+pub enum Verdict {
+    Allowed,
+    Limited {
+        scope: RateScope,
+        retry_after: Duration,
+    },
+}
+
+/// Limits for one connection: its own buckets plus the user's shared ones
+pub struct ConnectionRateLimiter {
+    user_id: i32,
+    config: RateLimitConfig,
+    buckets: HashMap<RateCategory, TokenBucket>,
+    shared: UserRateLimiter,
+    violations: VecDeque<Instant>,
+}
+
+impl ConnectionRateLimiter {
+    pub fn new(user_id: i32, config: RateLimitConfig, shared: UserRateLimiter) -> Self {
+        Self {
+            user_id,
+            config,
+            buckets: HashMap::new(),
+            shared,
+            violations: VecDeque::new(),
+        }
+    }
+
+    pub fn config(&self) -> &RateLimitConfig {
+        &self.config
+    }
+
+    /// Take a token for this frame from both the connection and the user bucket.
+    /// Nothing is taken unless both have one.
+    pub fn check(&mut self, category: RateCategory) -> Verdict {
+        let now = Instant::now();
+        let limit = self.config.limit(category);
+
+        let connection = self.buckets.entry(category).or_insert_with(|| TokenBucket::new(limit));
+        if !connection.has_token(now) {
+            return Verdict::Limited {
+                scope: RateScope::Connection,
+                retry_after: connection.retry_after(),
+            };
+        }
+
+        let mut shared = self.shared.buckets.lock().unwrap();
+        let user = shared
+            .entry((self.user_id, category))
+            .or_insert_with(|| TokenBucket::new(limit.per_user()));
+        if !user.has_token(now) {
+            return Verdict::Limited {
+                scope: RateScope::User,
+                retry_after: user.retry_after(),
+            };
+        }
+
+        user.take();
+        connection.take();
+        Verdict::Allowed
+    }
+
+    /// Record a violation; returns true once there have been too many recently
+    pub fn violation(&mut self) -> bool {
+        let now = Instant::now();
+        self.violations.push_back(now);
+        while let Some(&first) = self.violations.front() {
+            if now.duration_since(first) > self.config.violation_window {
+                self.violations.pop_front();
+            } else {
+                break;
+            }
+        }
+        self.violations.len() >= self.config.max_violations
+    }
+}
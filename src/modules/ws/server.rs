@@ -1,52 +1,285 @@
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
-use actix_ws::Session;
+use std::time::Duration;
+use actix_ws::{CloseCode, CloseReason};
+use crate::modules::ws::fanout::{FanoutBackend, InMemoryFanout};
+use crate::modules::ws::outbound::{Consumer, OutboundConfig, OutboundQueue};
+use crate::modules::ws::rate_limit::{ConnectionRateLimiter, RateLimitConfig, UserRateLimiter};
+use crate::modules::ws::type_def::{QueuedEvent, ServerEvent, WsClient};
+use crate::modules::ws::typing::{TypingConfig, TypingTracker};
 
 /// Shared chat server state to manage active connections
 #[derive(Clone)]
 pub struct ChatServer {
-    /// Map of User ID -> WebSocket Session
-    sessions: Arc<RwLock<HashMap<i32, Session>>>,
+    /// Map of User ID -> (Connection ID -> outbound queue), one entry per device
+    sessions: Arc<RwLock<HashMap<i32, HashMap<String, OutboundQueue>>>>,
+    outbound: OutboundConfig,
+    /// Reaches users connected to other nodes
+    fanout: Arc<dyn FanoutBackend>,
+    rate_limits: RateLimitConfig,
+    user_limits: UserRateLimiter,
+    typing: TypingTracker,
+    /// Connection ID -> (User ID, hash of the key that can resume it in place of a
+    /// login), for transports that can't authenticate a reconnect any other way
+    resume_keys: Arc<RwLock<HashMap<String, (i32, String)>>>,
+    shutting_down: Arc<AtomicBool>,
 }
 
 impl ChatServer {
+    /// Single-node hub
     pub fn new() -> Self {
-        Self {
+        Self::with_fanout(Arc::new(InMemoryFanout))
+    }
+
+    /// Hub that also exchanges frames with other nodes through `fanout`
+    pub fn with_fanout(fanout: Arc<dyn FanoutBackend>) -> Self {
+        let server = Self {
             sessions: Arc::new(RwLock::new(HashMap::new())),
+            outbound: OutboundConfig::from_env(),
+            fanout,
+            rate_limits: RateLimitConfig::from_env(),
+            user_limits: UserRateLimiter::default(),
+            typing: TypingTracker::new(TypingConfig::from_env()),
+            resume_keys: Arc::new(RwLock::new(HashMap::new())),
+            shutting_down: Arc::new(AtomicBool::new(false)),
+        };
+        server.fanout.subscribe(server.clone());
+        server
+    }
+
+    /// Register a new connection for a user (a user may be connected from several
+    /// devices). The transport drains the returned queue, starting from `queue.consumer()`.
+    pub fn join(&self, user_id: i32) -> (WsClient, OutboundQueue) {
+        let client = WsClient {
+            user_id,
+            connection_id: uuid::Uuid::new_v4().to_string(),
+        };
+        let queue = OutboundQueue::new(self.outbound);
+
+        let mut sessions = self.sessions.write().unwrap();
+        let devices = sessions.entry(user_id).or_default();
+        devices.insert(client.connection_id.clone(), queue.clone());
+
+        log::info!(
+            "User {} joined chat (connection {}, {} active)",
+            user_id,
+            client.connection_id,
+            devices.len()
+        );
+        (client, queue)
+    }
+
+    /// Remove a single connection, keeping the user's other devices
+    pub fn leave(&self, client: &WsClient) {
+        {
+            let mut sessions = self.sessions.write().unwrap();
+            if let Some(devices) = sessions.get_mut(&client.user_id) {
+                if let Some(queue) = devices.remove(&client.connection_id) {
+                    queue.close();
+                }
+                if devices.is_empty() {
+                    sessions.remove(&client.user_id);
+                    self.user_limits.forget(client.user_id);
+                }
+            }
+        }
+        self.resume_keys.write().unwrap().remove(&client.connection_id);
+        self.typing.connection_closed(self, client);
+        log::info!("User {} left chat (connection {})", client.user_id, client.connection_id);
+    }
+
+    /// The client dropped: keep buffering its frames for `resume_grace` in case it
+    /// resumes, then remove it. Does nothing if `consumer` was already replaced or the
+    /// connection is gone.
+    pub fn detach(&self, client: &WsClient, consumer: &Consumer) {
+        if self.connection(client).is_none() || !consumer.detach() {
+            return;
         }
+        log::info!("User {} detached (connection {})", client.user_id, client.connection_id);
+        self.typing.connection_closed(self, client);
+
+        let server = self.clone();
+        let client = client.clone();
+        let detached = consumer.id();
+        actix_rt::spawn(async move {
+            tokio::time::sleep(server.outbound.resume_grace).await;
+            let expired = server
+                .connection(&client)
+                .is_some_and(|queue| queue.current_consumer() == detached && !queue.is_attached());
+            if expired {
+                server.leave(&client);
+            }
+        });
+    }
+
+    /// Take over a connection for a client that has seen frames up to `last_seq`.
+    /// Returns None if it expired or the missed frames are no longer buffered.
+    pub fn resume(&self, user_id: i32, connection_id: &str, last_seq: u64) -> Option<(WsClient, Consumer)> {
+        let client = WsClient {
+            user_id,
+            connection_id: connection_id.to_string(),
+        };
+        let consumer = self.connection(&client)?.resume(last_seq)?;
+
+        log::info!("User {} resumed connection {}", user_id, connection_id);
+        Some((client, consumer))
+    }
+
+    /// Let whoever holds the key behind `key_hash` resume this connection with `resume_with_key`
+    pub fn set_resume_key(&self, client: &WsClient, key_hash: String) {
+        self.resume_keys
+            .write()
+            .unwrap()
+            .insert(client.connection_id.clone(), (client.user_id, key_hash));
+    }
+
+    /// Like `resume`, for a client that proves who it is with the connection's resume key.
+    /// The key is spent by the first attempt that presents it.
+    pub fn resume_with_key(&self, connection_id: &str, last_seq: u64, key_hash: &str) -> Option<(WsClient, Consumer)> {
+        let user_id = {
+            let mut keys = self.resume_keys.write().unwrap();
+            match keys.get(connection_id) {
+                Some((_, expected)) if expected == key_hash => keys.remove(connection_id)?.0,
+                _ => return None,
+            }
+        };
+        self.resume(user_id, connection_id, last_seq)
+    }
+
+    fn connection(&self, client: &WsClient) -> Option<OutboundQueue> {
+        self.sessions
+            .read()
+            .unwrap()
+            .get(&client.user_id)
+            .and_then(|devices| devices.get(&client.connection_id))
+            .cloned()
+    }
+
+    /// Inbound limits for a new connection, sharing the user's per-user buckets
+    pub fn rate_limiter(&self, user_id: i32) -> ConnectionRateLimiter {
+        ConnectionRateLimiter::new(user_id, self.rate_limits, self.user_limits.clone())
     }
 
-    /// Register a new session for a user
-    pub fn join(&self, user_id: i32, session: Session) {
-        log::info!("User {} joined chat", user_id);
-        self.sessions.write().unwrap().insert(user_id, session);
+    /// Typing indicator state, shared by every transport
+    pub fn typing(&self) -> &TypingTracker {
+        &self.typing
     }
 
-    /// Remove a user session
-    pub fn leave(&self, user_id: i32) {
-        log::info!("User {} left chat", user_id);
-        self.sessions.write().unwrap().remove(&user_id);
+    /// Longest message content accepted, in characters (WS_MAX_CONTENT_LENGTH)
+    pub fn max_content_length(&self) -> usize {
+        self.rate_limits.max_content_length
     }
 
-    /// Send a message to a specific user if they are connected
-    pub async fn send_message(&self, user_id: i32, message: &str) {
-        if let Some(session) = self.sessions.read().unwrap().get(&user_id) {
-            let mut session = session.clone();
-            let _ = session.text(message).await;
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// Drain every connection for a graceful shutdown: each client gets a
+    /// `ServerGoingAway` frame (reconnects are spread over `reconnect_spread`),
+    /// then whatever is still queued, then a 1001 close. Waits up to `grace`
+    /// for the writers to finish.
+    pub async fn shutdown(&self, grace: Duration, reconnect_spread: Duration) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+
+        let queues: Vec<OutboundQueue> = self
+            .sessions
+            .read()
+            .unwrap()
+            .values()
+            .flat_map(|devices| devices.values().cloned())
+            .collect();
+
+        log::info!("Draining {} WebSocket connections", queues.len());
+
+        let spread = reconnect_spread.as_millis() as u64;
+        let count = queues.len().max(1) as u64;
+        for (i, queue) in queues.iter().enumerate() {
+            let event = ServerEvent::ServerGoingAway {
+                reconnect_after_ms: spread * i as u64 / count,
+            };
+            queue.push(QueuedEvent::new(&event));
+            queue.close_with(CloseReason {
+                code: CloseCode::Away,
+                description: Some("Server shutting down".to_string()),
+            });
+        }
+
+        let drained = futures_util::future::join_all(queues.iter().map(|queue| queue.drained()));
+        if tokio::time::timeout(grace, drained).await.is_err() {
+            log::warn!("Some WebSocket connections did not drain within {:?}", grace);
         }
     }
 
-    /// Broadcast message to multiple users
-    pub async fn broadcast(&self, user_ids: &[i32], message: &str) {
+    /// Whether any of these users has a connection on this node, including detached
+    /// ones that are still buffering for a resume
+    pub fn has_connections(&self, user_ids: &[i32]) -> bool {
+        let sessions = self.sessions.read().unwrap();
+        user_ids.iter().any(|user_id| sessions.contains_key(user_id))
+    }
+
+    /// Whether a connection is on this node with a client attached
+    pub fn is_attached(&self, client: &WsClient) -> bool {
+        self.connection(client).is_some_and(|queue| queue.is_attached())
+    }
+
+    /// Queue an event for every device of a user, on this node and others
+    pub fn send_message(&self, user_id: i32, event: &ServerEvent) {
+        let frame = QueuedEvent::new(event);
+        self.deliver_local(&[user_id], &frame);
+        self.fanout.publish(&[user_id], frame.json());
+    }
+
+    /// Queue an event for one connection on this node, e.g. the `Ack` for a frame it sent
+    pub fn send_to_connection(&self, client: &WsClient, event: &ServerEvent) -> bool {
+        self.connection(client).is_some_and(|queue| queue.push(QueuedEvent::new(event)))
+    }
+
+    /// Queue an event for every device of several users, on this node and others
+    pub fn broadcast(&self, user_ids: &[i32], event: &ServerEvent) {
+        let frame = QueuedEvent::new(event);
+        self.deliver_local(user_ids, &frame);
+        self.fanout.publish(user_ids, frame.json());
+    }
+
+    /// Queue a frame for the users' connections on this node only; every queue
+    /// shares the one copy. Being queued is not delivery: writers confirm that.
+    pub fn deliver_local(&self, user_ids: &[i32], frame: &Arc<QueuedEvent>) {
         let sessions = self.sessions.read().unwrap();
         for user_id in user_ids {
-            if let Some(session) = sessions.get(user_id) {
-                let mut session = session.clone();
-                let message = message.to_string();
-                actix_rt::spawn(async move {
-                    let _ = session.text(message).await;
-                });
+            if let Some(devices) = sessions.get(user_id) {
+                for queue in devices.values() {
+                    queue.push(frame.clone());
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::ws::outbound::Outgoing;
+
+    #[actix_rt::test]
+    async fn detached_session_does_not_hold_up_shutdown() {
+        let srv = ChatServer::new();
+
+        // A connected client whose writer flushes and stops on close
+        let (_, connected) = srv.join(1);
+        let writer = connected.consumer();
+        actix_rt::spawn(async move {
+            while let Outgoing::Frame(..) = writer.recv().await {}
+            writer.finish();
+        });
+
+        // A client that dropped and hasn't resumed yet
+        let (client, dropped) = srv.join(2);
+        srv.detach(&client, &dropped.consumer());
+
+        let grace = Duration::from_secs(5);
+        let started = tokio::time::Instant::now();
+        srv.shutdown(grace, Duration::ZERO).await;
+        assert!(started.elapsed() < grace, "shutdown waited out the grace period");
+    }
+}
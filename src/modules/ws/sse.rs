@@ -0,0 +1,276 @@
+use std::time::Duration;
+use actix_web::{dev::ServiceRequest, web, web::Bytes, HttpMessage, HttpRequest, HttpResponse};
+use futures_util::{future::{select, Either}, stream};
+use tokio::sync::mpsc;
+use tokio::time::{interval, Interval};
+use crate::common::ErrorResponse;
+use crate::db::DbPool;
+use crate::modules::auth::services::AuthService;
+use crate::modules::chat::ChatService;
+use crate::modules::presence::PresenceService;
+use crate::modules::ws::outbound::{Consumer, Outgoing};
+use crate::modules::ws::server::ChatServer;
+use crate::modules::ws::type_def::{ServerEvent, WsClient};
+use crate::utils::hash_token;
+
+/// Comment sent when idle so proxies don't time the stream out
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(serde::Deserialize)]
+pub struct EventsQuery {
+    /// Single-use ticket from `POST /api/auth/ws-ticket` (EventSource can't set headers).
+    /// The next reconnect may send it once more to resume the session it opened.
+    pub ticket: Option<String>,
+    /// Same as the `Last-Event-ID` header, for clients that can't set it
+    pub last_event_id: Option<String>,
+}
+
+/// GET /api/events
+/// The events `/ws` pushes, as Server-Sent Events. Each event id is
+/// `<session_id>:<seq>`; reconnecting with `Last-Event-ID` replays what was missed.
+pub async fn events(
+    req: HttpRequest,
+    query: web::Query<EventsQuery>,
+    srv: web::Data<ChatServer>,
+    pool: web::Data<DbPool>,
+    presence: web::Data<PresenceService>,
+) -> HttpResponse {
+    if srv.is_shutting_down() {
+        return ErrorResponse::custom(
+            actix_web::http::StatusCode::SERVICE_UNAVAILABLE,
+            "Server is shutting down",
+            "Service Unavailable",
+        );
+    }
+
+    let last_event_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|h| h.to_str().ok())
+        .map(String::from)
+        .or_else(|| query.last_event_id.clone());
+    let resume_from = last_event_id.as_deref().and_then(parse_event_id);
+
+    // EventSource reconnects to the same URL, so a ticket already spent on this
+    // session resumes it one more time; it can't open a new one
+    let authenticated = req.extensions().get::<i32>().copied();
+    let mut redeemed_ticket = None;
+    let (user_id, resumed) = match (authenticated, &query.ticket) {
+        (Some(id), _) => (id, resume_from.and_then(|(session_id, seq)| srv.resume(id, session_id, seq))),
+        (None, Some(ticket)) => match resume_with_ticket(&srv, ticket, resume_from) {
+            Some((client, consumer)) => (client.user_id, Some((client, consumer))),
+            None => match AuthService::redeem_ws_ticket(&pool, ticket).await {
+                Ok(id) => {
+                    redeemed_ticket = Some(ticket);
+                    (id, resume_from.and_then(|(session_id, seq)| srv.resume(id, session_id, seq)))
+                }
+                Err(_) => return ErrorResponse::unauthorized("Invalid or expired ticket"),
+            },
+        },
+        (None, None) => return ErrorResponse::unauthorized("Unauthorized"),
+    };
+
+    let (client, consumer, resumed) = match resumed {
+        Some((client, consumer)) => (client, consumer, true),
+        None => {
+            let (client, queue) = srv.join(user_id);
+            (client, queue.consumer(), false)
+        }
+    };
+    if let Some(ticket) = redeemed_ticket {
+        srv.set_resume_key(&client, hash_token(ticket));
+    }
+    presence.connected(&pool, &srv, &client).await;
+
+    // Tells the client whether to fall back to a full sync; sent without an id
+    // so it doesn't move the client's Last-Event-ID
+    let session = ServerEvent::SessionStarted {
+        session_id: client.connection_id.clone(),
+        resumed,
+    };
+    let preamble = format!("retry: 3000\ndata: {}\n\n", session.to_json());
+
+    let state = StreamState {
+        delivered: ChatService::delivery_confirmations(&pool, &srv, user_id),
+        guard: Detach {
+            client,
+            consumer,
+            srv: srv.into_inner(),
+            pool: pool.into_inner(),
+            presence: presence.into_inner(),
+        },
+        keepalive: interval(KEEPALIVE_INTERVAL),
+        preamble: Some(preamble),
+        done: false,
+    };
+
+    let body = stream::unfold(state, |mut state| async move {
+        let chunk = state.next_chunk().await?;
+        Some((Ok::<_, actix_web::Error>(Bytes::from(chunk)), state))
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(body)
+}
+
+/// Resume the session named by `resume_from` if `ticket` is the one it was last
+/// opened or resumed with. Each ticket resumes at most once.
+fn resume_with_ticket(srv: &ChatServer, ticket: &str, resume_from: Option<(&str, u64)>) -> Option<(WsClient, Consumer)> {
+    let (session_id, seq) = resume_from?;
+    srv.resume_with_key(session_id, seq, &hash_token(ticket))
+}
+
+/// `%r` for the access log, without the query string of `/api/events` so tickets
+/// don't end up in the logs
+pub fn logged_request_line(req: &ServiceRequest) -> String {
+    let uri = if req.path() == "/api/events" {
+        req.path().to_string()
+    } else {
+        req.uri().to_string()
+    };
+    format!("{} {} {:?}", req.method(), uri, req.version())
+}
+
+/// Split `<session_id>:<seq>`
+fn parse_event_id(id: &str) -> Option<(&str, u64)> {
+    let (session_id, seq) = id.rsplit_once(':')?;
+    Some((session_id, seq.parse().ok()?))
+}
+
+struct StreamState {
+    guard: Detach,
+    /// Direct messages handed to the response stream, to be confirmed delivered
+    delivered: mpsc::UnboundedSender<i32>,
+    keepalive: Interval,
+    preamble: Option<String>,
+    done: bool,
+}
+
+impl StreamState {
+    async fn next_chunk(&mut self) -> Option<String> {
+        if let Some(preamble) = self.preamble.take() {
+            return Some(preamble);
+        }
+        if self.done {
+            return None;
+        }
+
+        let recv = self.guard.consumer.recv();
+        let tick = self.keepalive.tick();
+        futures_util::pin_mut!(recv, tick);
+
+        match select(recv, tick).await {
+            Either::Left((Outgoing::Frame(seq, frame), _)) => {
+                if let Some(message_id) = frame.direct_message_id() {
+                    let _ = self.delivered.send(message_id);
+                }
+                Some(format!("id: {}:{}\ndata: {}\n\n", self.guard.client.connection_id, seq, frame.json()))
+            }
+            Either::Left((Outgoing::Close(_), _)) => {
+                // Closed for good (shutdown or overflow): nothing to resume
+                self.done = true;
+                self.guard.srv.leave(&self.guard.client);
+                None
+            }
+            Either::Left((Outgoing::Detached, _)) => None,
+            Either::Right(_) => Some(": keepalive\n\n".to_string()),
+        }
+    }
+}
+
+/// Detaches the connection when the response stream is dropped (client went away)
+struct Detach {
+    client: WsClient,
+    consumer: Consumer,
+    srv: std::sync::Arc<ChatServer>,
+    pool: std::sync::Arc<DbPool>,
+    presence: std::sync::Arc<PresenceService>,
+}
+
+impl Drop for Detach {
+    fn drop(&mut self) {
+        // No-op if the connection was closed for good or resumed elsewhere
+        self.srv.detach(&self.client, &self.consumer);
+        self.consumer.finish();
+
+        let srv = self.srv.clone();
+        let pool = self.pool.clone();
+        let presence = self.presence.clone();
+        let client = self.client.clone();
+        actix_rt::spawn(async move {
+            presence.disconnected(&pool, &srv, &client).await;
+        });
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/events", web::get().to(events));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::ws::type_def::QueuedEvent;
+
+    #[actix_rt::test]
+    async fn reconnect_with_spent_ticket_resumes_its_session() {
+        let srv = ChatServer::new();
+        let (client, queue) = srv.join(7);
+        srv.set_resume_key(&client, hash_token("ticket-1"));
+        let consumer = queue.consumer();
+        queue.push(QueuedEvent::new(&ServerEvent::ServerGoingAway { reconnect_after_ms: 0 }));
+
+        // The stream drops; EventSource comes back with the same URL and Last-Event-ID
+        srv.detach(&client, &consumer);
+        let (resumed, consumer) = resume_with_ticket(&srv, "ticket-1", Some((&client.connection_id, 0)))
+            .expect("spent ticket should resume its own session");
+
+        assert_eq!(resumed.user_id, 7);
+        assert_eq!(resumed.connection_id, client.connection_id);
+        assert!(matches!(consumer.recv().await, Outgoing::Frame(1, _)));
+
+        // The ticket is spent now; a second drop needs a fresh one
+        srv.detach(&client, &consumer);
+        assert!(resume_with_ticket(&srv, "ticket-1", Some((&client.connection_id, 1))).is_none());
+    }
+
+    #[actix_rt::test]
+    async fn reconnect_with_other_ticket_is_refused() {
+        let srv = ChatServer::new();
+        let (client, queue) = srv.join(7);
+        srv.set_resume_key(&client, hash_token("ticket-1"));
+        srv.detach(&client, &queue.consumer());
+
+        assert!(resume_with_ticket(&srv, "ticket-2", Some((&client.connection_id, 0))).is_none());
+        assert!(resume_with_ticket(&srv, "ticket-1", None).is_none());
+    }
+
+    #[actix_rt::test]
+    async fn spent_ticket_does_not_outlive_its_session() {
+        let srv = ChatServer::new();
+        let (client, _queue) = srv.join(7);
+        srv.set_resume_key(&client, hash_token("ticket-1"));
+        srv.leave(&client);
+
+        assert!(resume_with_ticket(&srv, "ticket-1", Some((&client.connection_id, 0))).is_none());
+    }
+
+    #[test]
+    fn event_stream_query_is_left_out_of_the_access_log() {
+        let req = actix_web::test::TestRequest::with_uri("/api/events?ticket=secret&last_event_id=a:1").to_srv_request();
+        assert_eq!(logged_request_line(&req), "GET /api/events HTTP/1.1");
+
+        let req = actix_web::test::TestRequest::with_uri("/api/sync?since=3").to_srv_request();
+        assert_eq!(logged_request_line(&req), "GET /api/sync?since=3 HTTP/1.1");
+    }
+
+    #[test]
+    fn event_ids_split_into_session_and_seq() {
+        assert_eq!(parse_event_id("abc-def:12"), Some(("abc-def", 12)));
+        assert_eq!(parse_event_id("abc-def"), None);
+        assert_eq!(parse_event_id("abc-def:x"), None);
+    }
+}
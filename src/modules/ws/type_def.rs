@@ -1,6 +1,16 @@
-use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Serialize, Serializer};
+use chrono::{DateTime, Utc};
+use crate::modules::chat::model::Message;
+use crate::modules::presence::model::PresenceStatus;
+use crate::modules::ws::rate_limit::{RateCategory, RateScope};
+use crate::modules::sync::model::SyncEvent;
 
-/// WebSocket message types
+/// Version of the outbound event schema, sent as `v` on every server frame
+pub const EVENT_SCHEMA_VERSION: u8 = 1;
+
+/// WebSocket message types (client -> server)
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(tag = "type")]
 pub enum WsMessage {
@@ -8,10 +18,13 @@ pub enum WsMessage {
     TextMessage {
         to_user_id: i32,
         content: String,
+        /// Client-generated id echoed back in the `Ack`
+        client_msg_id: Option<String>,
     },
     GroupMessage {
         group_id: i32,
         content: String,
+        client_msg_id: Option<String>,
     },
     /// Typing indicator
     Typing {
@@ -19,21 +32,583 @@ pub enum WsMessage {
         group_id: Option<i32>,
         is_typing: bool,
     },
-    /// Read receipt
+    /// Read receipt for a single message from the other participant
     MessageRead {
         message_id: i32,
     },
-    /// User status
+    /// Mark everything the partner sent in a conversation read, up to and including this message
+    ConversationRead {
+        conversation_id: i32,
+        up_to_message_id: i32,
+    },
+    /// Group read receipt: everything up to and including this message has been read
+    GroupRead {
+        group_id: i32,
+        up_to_message_id: i32,
+    },
+    /// Edit one of your own messages (set group_id for group messages)
+    EditMessage {
+        message_id: i32,
+        group_id: Option<i32>,
+        content: String,
+    },
+    /// Delete a message for everyone (sender only) or just for yourself
+    DeleteMessage {
+        message_id: i32,
+        group_id: Option<i32>,
+        for_everyone: bool,
+    },
+    /// Set your own presence: online, away or dnd
     UserStatus {
+        status: PresenceStatus,
+    },
+    /// Request changes since a sync cursor (send on connect to catch up)
+    Sync {
+        since: Option<i64>,
+    },
+}
+
+/// Server -> client events
+#[derive(Debug, Serialize, Clone)]
+#[serde(tag = "type")]
+pub enum ServerEvent {
+    /// A direct message was persisted
+    MessageCreated {
+        message: Message,
+        conversation_id: i32,
+        sender_id: i32,
+    },
+    /// A group message was persisted
+    GroupMessageCreated {
+        message: Message,
+        group_id: i32,
+        sender_id: i32,
+    },
+    /// Confirms a send to the sending connection with the persisted id
+    Ack {
+        client_msg_id: Option<String>,
+        message_id: i32,
+        sent_at: DateTime<Utc>,
+    },
+    /// A message was edited by its sender
+    MessageEdited {
+        message: Message,
+        group_id: Option<i32>,
+    },
+    /// A message was deleted for everyone, or hidden on your other devices
+    MessageDeleted {
+        message_id: i32,
+        conversation_id: Option<i32>,
+        group_id: Option<i32>,
+        for_everyone: bool,
+    },
+    /// Typing indicator from another user
+    Typing {
         user_id: i32,
-        status: String, // online, offline, away
+        conversation_id: Option<i32>,
+        group_id: Option<i32>,
+        is_typing: bool,
+    },
+    /// A message you sent reached one of the recipient's devices
+    MessageDelivered {
+        message_id: i32,
+        conversation_id: i32,
+        delivered_at: DateTime<Utc>,
+    },
+    /// A message you sent was read (also sent to the reader's other devices)
+    MessageRead {
+        message_id: i32,
+        reader_id: i32,
+    },
+    /// Messages in a conversation were read up to and including `up_to_message_id`
+    ConversationRead {
+        conversation_id: i32,
+        reader_id: i32,
+        up_to_message_id: i32,
+        read_at: DateTime<Utc>,
+    },
+    /// A group member's read watermark moved forward
+    GroupRead {
+        group_id: i32,
+        reader_id: i32,
+        up_to_message_id: i32,
+        read_at: DateTime<Utc>,
+    },
+    /// A contact's presence changed (also sent to the user's own devices)
+    UserStatus {
+        user_id: i32,
+        status: PresenceStatus,
+        /// Set when the user went offline
+        last_seen: Option<DateTime<Utc>>,
     },
+    /// Reply to `Sync`; send another `Sync` with `cursor` while `has_more`
+    SyncBatch {
+        events: Vec<SyncEvent>,
+        cursor: i64,
+        has_more: bool,
+        /// `since` predates the retained feed; reload over REST and continue from `cursor`
+        resync_required: bool,
+    },
+    /// First frame of a connection. Pass `session_id` back to resume after a drop.
+    SessionStarted {
+        session_id: String,
+        /// True if this continues an earlier connection and missed events follow
+        resumed: bool,
+    },
+    /// The server is shutting down; reconnect (to another node) after the hint
+    ServerGoingAway {
+        reconnect_after_ms: u64,
+    },
+    /// A frame from this connection was dropped for exceeding a rate limit
+    RateLimited {
+        category: RateCategory,
+        scope: RateScope,
+        retry_after_ms: u64,
+    },
+    /// A request from this connection failed
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+/// Machine-readable error codes for `ServerEvent::Error`
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    SendFailed,
+    EditRejected,
+    DeleteRejected,
+    ReadRejected,
+    StatusRejected,
+    ContentTooLarge,
+    SyncFailed,
+    /// The frame could not be parsed as a message of the negotiated protocol version
+    UnsupportedMessage,
 }
 
-/// WebSocket client connection info
-#[allow(dead_code)]
+/// Versioned envelope every server frame is wrapped in
+#[derive(Debug, Serialize)]
+pub struct OutboundFrame<'a> {
+    pub v: u8,
+    #[serde(flatten)]
+    pub event: &'a ServerEvent,
+}
+
+impl ServerEvent {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        ServerEvent::Error {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Encode as a JSON text frame
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&OutboundFrame {
+            v: EVENT_SCHEMA_VERSION,
+            event: self,
+        })
+        .unwrap_or_default()
+    }
+}
+
+/// A server event ready to be queued: its v1 JSON (the canonical form SSE streams
+/// and the fanout relays) and the same frame as a JSON object. Built once per event
+/// and shared by every queue it goes to, so writers never parse it again.
+#[derive(Debug)]
+pub struct QueuedEvent {
+    json: String,
+    fields: serde_json::Map<String, serde_json::Value>,
+}
+
+impl QueuedEvent {
+    pub fn new(event: &ServerEvent) -> Arc<Self> {
+        // Serialized once; the fields are read back from that JSON, as for relayed frames
+        let json = event.to_json();
+        let fields = match serde_json::from_str(&json) {
+            Ok(serde_json::Value::Object(fields)) => fields,
+            _ => serde_json::Map::new(),
+        };
+        Arc::new(Self { json, fields })
+    }
+
+    /// Take a v1 frame relayed from another node
+    pub fn parse(json: String) -> Option<Arc<Self>> {
+        match serde_json::from_str(&json) {
+            Ok(serde_json::Value::Object(fields)) => Some(Arc::new(Self { json, fields })),
+            _ => None,
+        }
+    }
+
+    pub fn json(&self) -> &str {
+        &self.json
+    }
+
+    /// Id of the direct message this frame hands to its recipient, if it is a `MessageCreated`
+    pub fn direct_message_id(&self) -> Option<i32> {
+        if self.fields.get("type")?.as_str()? != "MessageCreated" {
+            return None;
+        }
+        let id = self.fields.get("message")?.get("id")?.as_i64()?;
+        i32::try_from(id).ok()
+    }
+}
+
+/// WebSocket protocol versions, negotiated with `Sec-WebSocket-Protocol` (see `Codec`).
+///
+/// Events are queued and relayed between nodes in the v1 encoding (`QueuedEvent`);
+/// each connection's codec lays them out when they are written.
+///
+/// - `chat.v1`: frames are flat objects, `{"type": "TextMessage", "to_user_id": 2, ...}`
+///   and `{"seq": 3, "v": 1, "type": "Ack", "message_id": 42, ...}`
+/// - `chat.v2`: the payload is kept apart from the envelope, `{"type": "TextMessage",
+///   "data": {"to_user_id": 2, ...}}` and `{"seq": 3, "v": 2, "type": "Ack", "data": {...}}`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProtocolVersion {
+    V1,
+    V2,
+}
+
+impl ProtocolVersion {
+    /// Spoken by clients that don't ask for a version
+    pub const DEFAULT: ProtocolVersion = ProtocolVersion::V1;
+    pub const SUPPORTED: [ProtocolVersion; 2] = [ProtocolVersion::V1, ProtocolVersion::V2];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ProtocolVersion::V1 => "chat.v1",
+            ProtocolVersion::V2 => "chat.v2",
+        }
+    }
+
+    /// Parse a client text frame
+    pub fn decode(self, text: &str) -> Result<WsMessage, String> {
+        match self {
+            // Parsed directly so errors keep their line and column
+            ProtocolVersion::V1 => serde_json::from_str(text).map_err(|e| e.to_string()),
+            ProtocolVersion::V2 => self.decode_value(serde_json::from_str(text).map_err(|e| e.to_string())?),
+        }
+    }
+
+    /// Parse a client frame that was already decoded from its wire format
+    pub fn decode_value(self, value: serde_json::Value) -> Result<WsMessage, String> {
+        match self {
+            ProtocolVersion::V1 => serde_json::from_value(value).map_err(|e| e.to_string()),
+            ProtocolVersion::V2 => {
+                let frame: InboundFrameV2 = serde_json::from_value(value).map_err(|e| e.to_string())?;
+                let mut message = match frame.data {
+                    serde_json::Value::Object(fields) => fields,
+                    serde_json::Value::Null => serde_json::Map::new(),
+                    _ => return Err("data must be an object".to_string()),
+                };
+                message.insert("type".to_string(), serde_json::Value::String(frame.kind));
+                serde_json::from_value(serde_json::Value::Object(message)).map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    /// Encode a queued event as JSON text for this version, with its `seq` if it has one
+    pub fn encode(self, frame: &QueuedEvent, seq: Option<u64>) -> Result<String, String> {
+        match (self, seq) {
+            (ProtocolVersion::V1, None) => Ok(frame.json.clone()),
+            // Cheaper than serializing the fields again for the common case
+            (ProtocolVersion::V1, Some(seq)) => match frame.json.strip_prefix('{') {
+                Some(rest) => Ok(format!("{{\"seq\":{},{}", seq, rest)),
+                None => Err("queued event is not a JSON object".to_string()),
+            },
+            (ProtocolVersion::V2, _) => serde_json::to_string(&self.layout(frame, seq)).map_err(|e| e.to_string()),
+        }
+    }
+
+    /// A queued event as this version lays it out, for the binary wire formats
+    fn layout(self, frame: &QueuedEvent, seq: Option<u64>) -> Layout<'_> {
+        Layout {
+            version: self,
+            seq,
+            fields: &frame.fields,
+        }
+    }
+}
+
+/// How frames are serialized on a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireFormat {
+    /// Text frames
+    Json,
+    /// Binary frames, MessagePack with string keys
+    MessagePack,
+    /// Binary frames, CBOR
+    Cbor,
+}
+
+impl WireFormat {
+    pub const SUPPORTED: [WireFormat; 3] = [WireFormat::Json, WireFormat::MessagePack, WireFormat::Cbor];
+
+    /// Appended to the version in the subprotocol name, e.g. `chat.v2+msgpack`
+    fn suffix(self) -> &'static str {
+        match self {
+            WireFormat::Json => "",
+            WireFormat::MessagePack => "+msgpack",
+            WireFormat::Cbor => "+cbor",
+        }
+    }
+
+    pub fn is_binary(self) -> bool {
+        self != WireFormat::Json
+    }
+}
+
+/// An encoded server frame
+pub enum WireFrame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Protocol version and wire format of a connection, negotiated together as one
+/// subprotocol: `chat.v2` is v2 as JSON, `chat.v2+msgpack` and `chat.v2+cbor` are
+/// the same frames in binary. The layout of each frame is the version's either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Codec {
+    pub version: ProtocolVersion,
+    pub format: WireFormat,
+}
+
+impl Codec {
+    /// Spoken by clients that don't ask for a protocol
+    pub const DEFAULT: Codec = Codec {
+        version: ProtocolVersion::DEFAULT,
+        format: WireFormat::Json,
+    };
+
+    pub fn name(self) -> String {
+        format!("{}{}", self.version.name(), self.format.suffix())
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        ProtocolVersion::SUPPORTED.into_iter().find_map(|version| {
+            let suffix = name.strip_prefix(version.name())?;
+            let format = WireFormat::SUPPORTED.into_iter().find(|f| f.suffix() == suffix)?;
+            Some(Codec { version, format })
+        })
+    }
+
+    /// Every subprotocol this server accepts
+    pub fn supported() -> Vec<String> {
+        ProtocolVersion::SUPPORTED
+            .into_iter()
+            .flat_map(|version| {
+                WireFormat::SUPPORTED.into_iter().map(move |format| Codec { version, format }.name())
+            })
+            .collect()
+    }
+
+    /// Parse a client frame; `binary` says which kind of WebSocket frame it came in
+    pub fn decode(self, frame: &[u8], binary: bool) -> Result<WsMessage, String> {
+        if binary != self.format.is_binary() {
+            let expected = if self.format.is_binary() { "binary" } else { "text" };
+            return Err(format!("{} uses {} frames", self.name(), expected));
+        }
+
+        match self.format {
+            WireFormat::Json => {
+                let text = std::str::from_utf8(frame).map_err(|e| e.to_string())?;
+                self.version.decode(text)
+            }
+            WireFormat::MessagePack => {
+                let value = rmp_serde::from_slice(frame).map_err(|e| e.to_string())?;
+                self.version.decode_value(value)
+            }
+            WireFormat::Cbor => {
+                let value = ciborium::de::from_reader(frame).map_err(|e| e.to_string())?;
+                self.version.decode_value(value)
+            }
+        }
+    }
+
+    /// Encode a queued event for this connection. Fails rather than produce an
+    /// empty or partial frame; the caller skips it.
+    pub fn encode(self, frame: &QueuedEvent, seq: Option<u64>) -> Result<WireFrame, String> {
+        match self.format {
+            WireFormat::Json => self.version.encode(frame, seq).map(WireFrame::Text),
+            WireFormat::MessagePack => {
+                let layout = self.version.layout(frame, seq);
+                rmp_serde::to_vec_named(&layout).map(WireFrame::Binary).map_err(|e| e.to_string())
+            }
+            WireFormat::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::ser::into_writer(&self.version.layout(frame, seq), &mut bytes).map_err(|e| e.to_string())?;
+                Ok(WireFrame::Binary(bytes))
+            }
+        }
+    }
+}
+
+/// Client frame in `chat.v2`
+#[derive(Debug, Deserialize)]
+struct InboundFrameV2 {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    data: serde_json::Value,
+}
+
+/// A queued event laid out for one connection, serialized straight from the shared
+/// fields without copying them:
+/// v1 `{"seq": 3, "v": 1, "type": "Ack", ...}`, v2 `{"seq": 3, "v": 2, "type": "Ack", "data": {...}}`
+struct Layout<'a> {
+    version: ProtocolVersion,
+    seq: Option<u64>,
+    fields: &'a serde_json::Map<String, serde_json::Value>,
+}
+
+impl Serialize for Layout<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let seq_len = usize::from(self.seq.is_some());
+        match self.version {
+            ProtocolVersion::V1 => {
+                let mut map = serializer.serialize_map(Some(seq_len + self.fields.len()))?;
+                if let Some(seq) = self.seq {
+                    map.serialize_entry("seq", &seq)?;
+                }
+                for (key, value) in self.fields {
+                    map.serialize_entry(key, value)?;
+                }
+                map.end()
+            }
+            ProtocolVersion::V2 => {
+                let mut map = serializer.serialize_map(Some(seq_len + 3))?;
+                if let Some(seq) = self.seq {
+                    map.serialize_entry("seq", &seq)?;
+                }
+                map.serialize_entry("v", &2)?;
+                map.serialize_entry("type", self.fields.get("type").unwrap_or(&serde_json::Value::Null))?;
+                map.serialize_entry("data", &Payload(self.fields))?;
+                map.end()
+            }
+        }
+    }
+}
+
+/// A v1 frame's fields without its envelope (`v` and `type`)
+struct Payload<'a>(&'a serde_json::Map<String, serde_json::Value>);
+
+impl Serialize for Payload<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let fields = self.0.iter().filter(|(key, _)| *key != "v" && *key != "type");
+        let len = fields.clone().count();
+        let mut map = serializer.serialize_map(Some(len))?;
+        for (key, value) in fields {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+/// WebSocket client connection info (one per device)
 #[derive(Debug, Clone)]
 pub struct WsClient {
     pub user_id: i32,
     pub connection_id: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn codecs() -> Vec<Codec> {
+        Codec::supported().iter().map(|name| Codec::from_name(name).unwrap()).collect()
+    }
+
+    /// Read a server frame back into JSON, whatever format it was written in
+    fn read_frame(codec: Codec, frame: WireFrame) -> Value {
+        match (codec.format, frame) {
+            (WireFormat::Json, WireFrame::Text(text)) => serde_json::from_str(&text).unwrap(),
+            (WireFormat::MessagePack, WireFrame::Binary(bytes)) => rmp_serde::from_slice(&bytes).unwrap(),
+            (WireFormat::Cbor, WireFrame::Binary(bytes)) => ciborium::de::from_reader(bytes.as_slice()).unwrap(),
+            _ => panic!("{} wrote the wrong kind of frame", codec.name()),
+        }
+    }
+
+    /// Write a client frame the way a client speaking `codec` would
+    fn write_frame(codec: Codec, value: &Value) -> Vec<u8> {
+        match codec.format {
+            WireFormat::Json => serde_json::to_vec(value).unwrap(),
+            WireFormat::MessagePack => rmp_serde::to_vec_named(value).unwrap(),
+            WireFormat::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::ser::into_writer(value, &mut bytes).unwrap();
+                bytes
+            }
+        }
+    }
+
+    #[test]
+    fn server_frames_round_trip_in_every_codec() {
+        let frame = QueuedEvent::new(&ServerEvent::Typing {
+            user_id: 4,
+            conversation_id: Some(9),
+            group_id: None,
+            is_typing: true,
+        });
+
+        for codec in codecs() {
+            let value = read_frame(codec, codec.encode(&frame, Some(3)).unwrap());
+            let expected = match codec.version {
+                ProtocolVersion::V1 => json!({
+                    "seq": 3, "v": 1, "type": "Typing",
+                    "user_id": 4, "conversation_id": 9, "group_id": null, "is_typing": true,
+                }),
+                ProtocolVersion::V2 => json!({
+                    "seq": 3, "v": 2, "type": "Typing",
+                    "data": { "user_id": 4, "conversation_id": 9, "group_id": null, "is_typing": true },
+                }),
+            };
+            assert_eq!(value, expected, "{}", codec.name());
+        }
+    }
+
+    #[test]
+    fn frames_without_seq_leave_it_out() {
+        let frame = QueuedEvent::new(&ServerEvent::ServerGoingAway { reconnect_after_ms: 500 });
+
+        for codec in codecs() {
+            let value = read_frame(codec, codec.encode(&frame, None).unwrap());
+            assert!(value.get("seq").is_none(), "{}", codec.name());
+            assert_eq!(value["type"], "ServerGoingAway", "{}", codec.name());
+        }
+    }
+
+    #[test]
+    fn client_frames_decode_in_every_codec() {
+        for codec in codecs() {
+            let value = match codec.version {
+                ProtocolVersion::V1 => json!({
+                    "type": "TextMessage", "to_user_id": 2, "content": "hi", "client_msg_id": "c-1",
+                }),
+                ProtocolVersion::V2 => json!({
+                    "type": "TextMessage",
+                    "data": { "to_user_id": 2, "content": "hi", "client_msg_id": "c-1" },
+                }),
+            };
+
+            let message = codec.decode(&write_frame(codec, &value), codec.format.is_binary()).unwrap();
+            match message {
+                WsMessage::TextMessage { to_user_id, content, client_msg_id } => {
+                    assert_eq!((to_user_id, content.as_str()), (2, "hi"), "{}", codec.name());
+                    assert_eq!(client_msg_id.as_deref(), Some("c-1"), "{}", codec.name());
+                }
+                other => panic!("{} decoded {:?}", codec.name(), other),
+            }
+        }
+    }
+
+    #[test]
+    fn frames_of_the_other_kind_are_refused() {
+        for codec in codecs() {
+            let frame = write_frame(codec, &json!({ "type": "Sync", "since": null }));
+            assert!(codec.decode(&frame, !codec.format.is_binary()).is_err(), "{}", codec.name());
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::modules::ws::server::ChatServer;
+use crate::modules::ws::type_def::{ServerEvent, WsClient};
+
+/// Typing settings, read from WS_TYPING_TIMEOUT, WS_TYPING_THROTTLE and
+/// WS_MEMBERSHIP_CACHE_TTL (all in seconds)
+#[derive(Debug, Clone, Copy)]
+pub struct TypingConfig {
+    /// Typing stops on its own if not refreshed within this long
+    pub timeout: Duration,
+    /// Repeated `is_typing: true` within this long are not relayed again
+    pub throttle: Duration,
+    /// How long a conversation's or group's participants are cached
+    pub members_ttl: Duration,
+}
+
+impl TypingConfig {
+    pub fn from_env() -> Self {
+        let secs = |key: &str, default: u64| {
+            Duration::from_secs(
+                env::var(key)
+                    .ok()
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .filter(|&v| v > 0)
+                    .unwrap_or(default),
+            )
+        };
+
+        Self {
+            timeout: secs("WS_TYPING_TIMEOUT", 6),
+            throttle: secs("WS_TYPING_THROTTLE", 3),
+            members_ttl: secs("WS_MEMBERSHIP_CACHE_TTL", 30),
+        }
+    }
+}
+
+/// Where someone is typing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TypingTarget {
+    Conversation(i32),
+    Group(i32),
+}
+
+impl TypingTarget {
+    fn event(self, user_id: i32, is_typing: bool) -> ServerEvent {
+        let (conversation_id, group_id) = match self {
+            TypingTarget::Conversation(id) => (Some(id), None),
+            TypingTarget::Group(id) => (None, Some(id)),
+        };
+        ServerEvent::Typing {
+            user_id,
+            conversation_id,
+            group_id,
+            is_typing,
+        }
+    }
+}
+
+/// Someone currently shown as typing
+struct Typing {
+    recipients: Vec<i32>,
+    /// Connections the indicator came from; REST refreshes add none
+    origins: HashSet<String>,
+    deadline: Instant,
+    relayed_at: Instant,
+    /// Tells a stale expiry timer apart from the current one
+    epoch: u64,
+}
+
+/// Participants of a conversation or group as last read from the database
+struct CachedParticipants {
+    cached_at: Instant,
+    ids: Vec<i32>,
+}
+
+/// Coalesces typing indicators and stops them when they time out or their
+/// connection goes away, so clients never see one stuck on
+#[derive(Clone)]
+pub struct TypingTracker {
+    config: TypingConfig,
+    typing: Arc<Mutex<HashMap<(i32, TypingTarget), Typing>>>,
+    members: Arc<Mutex<HashMap<TypingTarget, CachedParticipants>>>,
+    epochs: Arc<AtomicU64>,
+}
+
+impl TypingTracker {
+    pub fn new(config: TypingConfig) -> Self {
+        Self {
+            config,
+            typing: Arc::new(Mutex::new(HashMap::new())),
+            members: Arc::new(Mutex::new(HashMap::new())),
+            epochs: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Cached participants of a conversation or members of a group, if still fresh
+    pub fn participants(&self, target: TypingTarget) -> Option<Vec<i32>> {
+        let members = self.members.lock().unwrap();
+        members
+            .get(&target)
+            .filter(|cached| cached.cached_at.elapsed() < self.config.members_ttl)
+            .map(|cached| cached.ids.clone())
+    }
+
+    pub fn remember_participants(&self, target: TypingTarget, ids: Vec<i32>) {
+        let mut members = self.members.lock().unwrap();
+        let ttl = self.config.members_ttl;
+        members.retain(|_, cached| cached.cached_at.elapsed() < ttl);
+        members.insert(target, CachedParticipants {
+            cached_at: Instant::now(),
+            ids,
+        });
+    }
+
+    /// Drop the cached members of a group whose membership changed
+    pub fn forget_participants(&self, target: TypingTarget) {
+        self.members.lock().unwrap().remove(&target);
+    }
+
+    /// Apply a typing indicator from `user_id`. Starts and stops are relayed to
+    /// `recipients`; repeats within the throttle window only extend the timeout.
+    pub fn update(
+        &self,
+        srv: &ChatServer,
+        user_id: i32,
+        target: TypingTarget,
+        recipients: Vec<i32>,
+        is_typing: bool,
+        origin: Option<&WsClient>,
+    ) {
+        let key = (user_id, target);
+        let now = Instant::now();
+        let mut typing = self.typing.lock().unwrap();
+
+        if !is_typing {
+            // A stop for someone not typing has nothing to clear
+            if let Some(entry) = typing.remove(&key) {
+                drop(typing);
+                srv.broadcast(&entry.recipients, &target.event(user_id, false));
+            }
+            return;
+        }
+
+        if let Some(entry) = typing.get_mut(&key) {
+            entry.deadline = now + self.config.timeout;
+            if let Some(client) = origin {
+                entry.origins.insert(client.connection_id.clone());
+            }
+            if now.duration_since(entry.relayed_at) < self.config.throttle {
+                return;
+            }
+            // Still typing: relay again so recipients' own timers don't run out
+            entry.relayed_at = now;
+            entry.recipients = recipients;
+            let recipients = entry.recipients.clone();
+            drop(typing);
+            srv.broadcast(&recipients, &target.event(user_id, true));
+            return;
+        }
+
+        let epoch = self.epochs.fetch_add(1, Ordering::Relaxed);
+        typing.insert(key, Typing {
+            recipients: recipients.clone(),
+            origins: origin.map(|client| client.connection_id.clone()).into_iter().collect(),
+            deadline: now + self.config.timeout,
+            relayed_at: now,
+            epoch,
+        });
+        drop(typing);
+
+        srv.broadcast(&recipients, &target.event(user_id, true));
+        actix_rt::spawn(self.clone().expire(srv.clone(), key, epoch));
+    }
+
+    /// Stop everything typed only from a connection that closed or dropped;
+    /// indicators another connection is still refreshing stay on
+    pub fn connection_closed(&self, srv: &ChatServer, client: &WsClient) {
+        let mut stopped = Vec::new();
+        self.typing.lock().unwrap().retain(|&(user_id, target), entry| {
+            if entry.origins.remove(&client.connection_id) && entry.origins.is_empty() {
+                stopped.push((user_id, target, entry.recipients.clone()));
+                false
+            } else {
+                true
+            }
+        });
+
+        for (user_id, target, recipients) in stopped {
+            srv.broadcast(&recipients, &target.event(user_id, false));
+        }
+    }
+
+    /// Relay `is_typing: false` once the indicator has gone unrefreshed for the timeout
+    async fn expire(self, srv: ChatServer, key: (i32, TypingTarget), epoch: u64) {
+        loop {
+            let deadline = match self.typing.lock().unwrap().get(&key) {
+                Some(entry) if entry.epoch == epoch => entry.deadline,
+                _ => return, // Stopped, or replaced by a newer indicator
+            };
+            tokio::time::sleep_until(deadline.into()).await;
+
+            let mut typing = self.typing.lock().unwrap();
+            let expired = typing
+                .get(&key)
+                .is_some_and(|entry| entry.epoch == epoch && entry.deadline <= Instant::now());
+            if expired {
+                let entry = typing.remove(&key).unwrap();
+                drop(typing);
+                let (user_id, target) = key;
+                srv.broadcast(&entry.recipients, &target.event(user_id, false));
+                return;
+            }
+        }
+    }
+}
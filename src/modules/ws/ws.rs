@@ -1,4 +1,4 @@
-use actix_ws::Message;
+use actix_ws::{CloseCode, CloseReason, Message, ProtocolError};
 use futures_util::{
     future::{select, Either},
     StreamExt as _,
@@ -6,10 +6,28 @@ use futures_util::{
 use tokio::{pin, time::interval};
 use std::time::{Duration, Instant};
 use actix_web::{web, HttpRequest, HttpResponse, Error};
-use crate::modules::ws::type_def::WsMessage;
+use actix_web::http::header::{HeaderValue, SEC_WEBSOCKET_PROTOCOL};
+use crate::modules::ws::type_def::{Codec, ErrorCode, QueuedEvent, ServerEvent, WsClient, WsMessage};
+use crate::modules::ws::outbound::{self, OutboundQueue};
+use crate::modules::ws::rate_limit::{ConnectionRateLimiter, RateCategory, Verdict};
 use crate::modules::ws::server::ChatServer;
 use crate::db::DbPool;
-use crate::modules::chat::MessageRepository;
+use crate::common::ErrorResponse;
+use crate::modules::auth::services::AuthService;
+use crate::modules::chat::ChatService;
+use crate::modules::presence::PresenceService;
+use crate::modules::sync::SyncRepository;
+
+/// Maximum number of events returned per `Sync` frame
+const SYNC_BATCH_SIZE: i64 = 200;
+
+/// Reconnect parameters: `?resume=<session_id>&last_seq=<n>`, from the
+/// `SessionStarted` frame and the `seq` of the last frame received
+#[derive(serde::Deserialize)]
+struct ResumeQuery {
+    resume: Option<String>,
+    last_seq: Option<u64>,
+}
 
 /// WebSocket handshake and start endpoint
 pub async fn start_connection(
@@ -17,29 +35,102 @@ pub async fn start_connection(
     stream: web::Payload,
     srv: web::Data<ChatServer>,
     pool: web::Data<DbPool>,
+    presence: web::Data<PresenceService>,
 ) -> Result<HttpResponse, Error> {
-    let (res, session, stream) = actix_ws::handle(&req, stream)?;
+    if srv.is_shutting_down() {
+        return Ok(ErrorResponse::custom(
+            actix_web::http::StatusCode::SERVICE_UNAVAILABLE,
+            "Server is shutting down",
+            "Service Unavailable",
+        ));
+    }
+
+    // Authenticate before upgrading, so unauthenticated clients get a plain 401
+    let credential = match handshake::extract_credential(&req) {
+        Some(credential) => credential,
+        None => {
+            log::warn!("Connection rejected: No credentials provided");
+            return Ok(ErrorResponse::unauthorized("No token provided"));
+        }
+    };
+
+    let requested_codec = match handshake::requested_codec(&req) {
+        Ok(codec) => codec,
+        Err(message) => return Ok(ErrorResponse::bad_request(&message)),
+    };
+
+    let auth_result = match &credential {
+        handshake::Credential::Bearer(token) | handshake::Credential::Subprotocol(token) => {
+            AuthService::authenticate(&pool, token).await.map(|claims| claims.sub)
+        }
+        handshake::Credential::Ticket(ticket) => AuthService::redeem_ws_ticket(&pool, ticket).await,
+    };
+
+    let user_id = match auth_result {
+        Ok(id) => id,
+        Err(e) => {
+            log::warn!("Connection rejected: {}", e);
+            return Ok(ErrorResponse::unauthorized("Invalid or expired token"));
+        }
+    };
+
+    let mut limiter = srv.rate_limiter(user_id);
+    let (mut res, session, stream) = actix_ws::handle(&req, stream)?;
+    // Oversized frames are refused while being read rather than buffered first
+    let stream = stream.max_frame_size(limiter.config().max_frame_size);
 
-    // Ideally, extract user_id from JWT here
-    // For now, we'll parse it from query params: /ws?userId=1
-    // Fallback to 0 if not provided (should be handled better in prod)
-    let q_str = req.query_string();
-    let user_id = qvec::extract_user_id_from_query(q_str).unwrap_or(0);
-    
-    if user_id == 0 {
-        log::warn!("Connection rejected: No user_id provided");
-        return Ok(HttpResponse::Unauthorized().finish());
+    // Browsers require the server to confirm one of the offered subprotocols
+    let confirmed = match (requested_codec, &credential) {
+        (Some(codec), _) => HeaderValue::from_str(&codec.name()).ok(),
+        (None, handshake::Credential::Subprotocol(_)) => Some(HeaderValue::from_static(handshake::BEARER_PROTOCOL)),
+        (None, _) => None,
+    };
+    if let Some(protocol) = confirmed {
+        res.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, protocol);
     }
+    let codec = requested_codec.unwrap_or(Codec::DEFAULT);
 
-    // Register session
-    srv.join(user_id, session.clone());
+    // Pick up a dropped session where it left off, or start a new one
+    let resume = web::Query::<ResumeQuery>::from_query(req.query_string()).ok();
+    let resumed = resume
+        .as_ref()
+        .and_then(|q| Some((q.resume.as_deref()?, q.last_seq.unwrap_or(0))))
+        .and_then(|(session_id, last_seq)| srv.resume(user_id, session_id, last_seq));
+
+    let (client, consumer, resumed) = match resumed {
+        Some((client, consumer)) => (client, consumer, true),
+        None => {
+            let (client, queue) = srv.join(user_id);
+            (client, queue.consumer(), false)
+        }
+    };
+    let outbound = consumer.queue().clone();
+
+    // Sent ahead of the queue (replayed frames follow it) and without a seq
+    let started = ServerEvent::SessionStarted {
+        session_id: client.connection_id.clone(),
+        resumed,
+    };
+    let mut writer = session.clone();
+    match codec.encode(&QueuedEvent::new(&started), None) {
+        Ok(encoded) => {
+            let _ = outbound::send(&mut writer, encoded).await;
+        }
+        Err(e) => log::error!("Skipping SessionStarted that failed to encode as {}: {}", codec.name(), e),
+    }
+    let delivered = ChatService::delivery_confirmations(&pool, &srv, user_id);
+    actix_rt::spawn(consumer.clone().write_to(writer, codec, delivered));
+
+    presence.connected(&pool, &srv, &client).await;
 
     // Spawn websocket handler task
     actix_rt::spawn(async move {
         let mut tick_interval = interval(Duration::from_secs(5));
         let mut last_heartbeat = Instant::now();
         let mut session = session.clone();
-        
+        // Whether the client dropped without closing, so may come back with `?resume=`
+        let mut dropped = false;
+
         pin!(stream);
 
         loop {
@@ -49,169 +140,314 @@ pub async fn start_connection(
             // Wait for either stream message or heartbeat tick
             match select(stream.next(), tick).await {
                 Either::Left((Some(Ok(msg)), _)) => {
-                    match msg {
-                        Message::Text(text) => {
-                            // Parse incoming message
-                            if let Ok(ws_msg) = serde_json::from_str::<WsMessage>(&text) {
-                                match ws_msg {
-                                    WsMessage::TextMessage { to_user_id, content } => {
-                                        log::info!("Message from {} to {}: {}", user_id, to_user_id, content);
-                                        
-                                        // 1. Save to Database
-                                        let save_result = MessageRepository::create_message(
-                                            &pool, 
-                                            user_id, 
-                                            to_user_id, 
-                                            &content
-                                        ).await;
-
-                                        match save_result {
-                                            Ok(_saved_msg) => {
-                                                // 2. Route to Recipient (if online)
-                                                let payload = serde_json::to_string(&WsMessage::TextMessage {
-                                                    to_user_id: user_id, // From sender perspective
-                                                    content: content.clone(),
-                                                }).unwrap_or_default();
-                                                
-                                                srv.send_message(to_user_id, &payload).await;
-                                                
-                                                // 3. Ack to Sender
-                                                let _ = session.text(format!("Sent: {}", content)).await;
-                                            },
-                                            Err(e) => {
-                                                log::error!("Failed to save message: {}", e);
-                                                let _ = session.text("Error: Failed to send message").await;
-                                            }
-                                        }
-                                    },
-                                    WsMessage::GroupMessage { group_id, content } => {
-                                        log::info!("Group Message from {} to group {}: {}", user_id, group_id, content);
-
-                                        // 1. Save to Group DB
-                                        let save_result = MessageRepository::create_group_message(
-                                            &pool,
-                                            user_id,
-                                            group_id,
-                                            &content
-                                        ).await;
-
-                                        match save_result {
-                                            Ok(_saved_msg) => {
-                                                // 2. Get Members
-                                                if let Ok(members) = MessageRepository::get_group_members(&pool, group_id).await {
-                                                    // 3. Broadcast to all members (except sender ideally, but broadcast handles connection check)
-                                                    let payload = serde_json::to_string(&WsMessage::GroupMessage {
-                                                        group_id,
-                                                        content: format!("{}: {}", user_id, content), // Simple format for now
-                                                    }).unwrap_or_default();
-                                                    
-                                                    // Filter out sender from broadcast list to avoid duplicate echo
-                                                    let recipients: Vec<i32> = members.into_iter().filter(|&id| id != user_id).collect();
-                                                    srv.broadcast(&recipients, &payload).await;
-                                                    
-                                                    let _ = session.text(format!("Sent Group: {}", content)).await;
-                                                }
-                                            },
-                                            Err(e) => {
-                                                 log::error!("Failed to save group message: {}", e);
-                                                 let _ = session.text(format!("Error: {}", e)).await;
-                                            }
-                                        }
-                                    }
-                                    WsMessage::Typing { conversation_id, group_id, is_typing } => {
-                                        // 1. Group Typing
-                                        if let Some(g_id) = group_id {
-                                            if let Ok(members) = MessageRepository::get_group_members(&pool, g_id).await {
-                                                let payload = serde_json::to_string(&WsMessage::Typing {
-                                                    conversation_id: None,
-                                                    group_id: Some(g_id),
-                                                    is_typing,
-                                                }).unwrap_or_default();
-                                                
-                                                let recipients: Vec<i32> = members.into_iter().filter(|&id| id != user_id).collect();
-                                                srv.broadcast(&recipients, &payload).await;
-                                            }
-                                        }
-                                        // 2. 1-to-1 Typing
-                                        else if let Some(c_id) = conversation_id {
-                                             if let Ok(Some(partner_id)) = MessageRepository::get_conversation_partner(&pool, c_id, user_id).await {
-                                                 let payload = serde_json::to_string(&WsMessage::Typing {
-                                                    conversation_id: Some(c_id),
-                                                    group_id: None,
-                                                    is_typing,
-                                                }).unwrap_or_default();
-                                                
-                                                srv.send_message(partner_id, &payload).await;
-                                             }
-                                        }
-                                    },
-                                    WsMessage::MessageRead { message_id } => {
-                                        // 1. Mark in DB
-                                        if let Ok(Some(sender_id)) = MessageRepository::mark_message_read(&pool, message_id, user_id).await {
-                                            // 2. Notify Sender
-                                            let payload = serde_json::to_string(&WsMessage::MessageRead {
-                                                message_id
-                                            }).unwrap_or_default();
-                                            
-                                            srv.send_message(sender_id, &payload).await;
-                                        }
-                                    },
-                                    _ => {}
-                                }
-                            }
-                        }
+                    let (frame, binary) = match msg {
+                        Message::Text(text) => (text.into_bytes(), false),
+                        Message::Binary(bytes) => (bytes, true),
                         Message::Ping(bytes) => {
                             last_heartbeat = Instant::now();
                             let _ = session.pong(&bytes).await;
+                            continue;
                         }
                         Message::Pong(_) => {
                             last_heartbeat = Instant::now();
+                            continue;
                         }
                         Message::Close(reason) => {
-                            srv.leave(user_id);
                             let _ = session.close(reason).await;
                             break;
                         }
-                        _ => {}
+                        _ => continue,
+                    };
+
+                    // Parse incoming message
+                    let rejection = match codec.decode(&frame, binary) {
+                        Ok(ws_msg) => match admit(&mut limiter, &ws_msg) {
+                            None => {
+                                handle_ws_message(ws_msg, &client, &outbound, &srv, &pool, &presence).await;
+                                continue;
+                            }
+                            Some(rejection) => rejection,
+                        },
+                        Err(e) => ServerEvent::error(
+                            ErrorCode::UnsupportedMessage,
+                            format!("Unsupported {} message: {}", codec.name(), e),
+                        ),
+                    };
+
+                    reply(&outbound, &rejection);
+                    if limiter.violation() {
+                        log::warn!("Closing connection of user {}: repeated violations", user_id);
+                        let _ = session.close(Some(CloseReason {
+                            code: CloseCode::Policy,
+                            description: Some("Too many rejected frames".to_string()),
+                        })).await;
+                        break;
                     }
                 }
+                Either::Left((Some(Err(ProtocolError::Overflow)), _)) => {
+                    log::warn!("Closing connection of user {}: frame too large", user_id);
+                    let _ = session.close(Some(CloseReason {
+                        code: CloseCode::Size,
+                        description: Some("Frame too large".to_string()),
+                    })).await;
+                    break;
+                }
                 Either::Left((Some(Err(e)), _)) => {
                     log::error!("WS error: {}", e);
-                    srv.leave(user_id);
+                    dropped = true;
                     break;
                 }
                 Either::Left((None, _)) => {
-                    srv.leave(user_id);
+                    dropped = true;
                     break;
-                },
+                }
                 Either::Right((_inst, _)) => {
                     // Check heartbeat
                     if last_heartbeat.elapsed() > Duration::from_secs(10) {
                          log::info!("WS client heartbeat timed out");
-                         srv.leave(user_id);
                          let _ = session.close(None).await;
+                         dropped = true;
                          break;
                     }
                     let _ = session.ping(b"").await;
                 }
             }
         }
+
+        // A resume may already have taken the connection over
+        if dropped {
+            srv.detach(&client, &consumer);
+        } else if consumer.is_current() {
+            srv.leave(&client);
+        }
+        presence.disconnected(&pool, &srv, &client).await;
     });
 
     Ok(res)
 }
 
-// Helper to handle query extraction manually since we're inside the handler
-mod qvec {
-    pub fn extract_user_id_from_query(query: &str) -> Option<i32> {
+/// Check a frame against the connection's limits before it touches the database.
+/// Returns the event to send back if it is rejected.
+fn admit(limiter: &mut ConnectionRateLimiter, ws_msg: &WsMessage) -> Option<ServerEvent> {
+    let content = match ws_msg {
+        WsMessage::TextMessage { content, .. }
+        | WsMessage::GroupMessage { content, .. }
+        | WsMessage::EditMessage { content, .. } => Some(content),
+        _ => None,
+    };
+    let max_length = limiter.config().max_content_length;
+    if content.is_some_and(|c| c.chars().count() > max_length) {
+        return Some(ServerEvent::error(
+            ErrorCode::ContentTooLarge,
+            format!("Message content is limited to {} characters", max_length),
+        ));
+    }
+
+    let category = RateCategory::of(ws_msg);
+    match limiter.check(category) {
+        Verdict::Allowed => None,
+        Verdict::Limited { scope, retry_after } => Some(ServerEvent::RateLimited {
+            category,
+            scope,
+            retry_after_ms: retry_after.as_millis() as u64,
+        }),
+    }
+}
+
+/// Handle a single parsed frame from a connected user
+async fn handle_ws_message(
+    ws_msg: WsMessage,
+    client: &WsClient,
+    out: &OutboundQueue,
+    srv: &ChatServer,
+    pool: &DbPool,
+    presence: &PresenceService,
+) {
+    let user_id = client.user_id;
+    match ws_msg {
+        WsMessage::TextMessage { to_user_id, content, client_msg_id } => {
+            if let Err(e) = ChatService::send_direct(pool, srv, user_id, to_user_id, &content, client_msg_id, Some(client)).await {
+                reply(out, &send_error(e));
+            }
+        },
+        WsMessage::GroupMessage { group_id, content, client_msg_id } => {
+            if let Err(e) = ChatService::send_group(pool, srv, user_id, group_id, &content, client_msg_id, Some(client)).await {
+                reply(out, &send_error(e));
+            }
+        },
+        WsMessage::Typing { conversation_id, group_id, is_typing } => {
+            // Typing is best effort, so a rejected indicator isn't worth an error frame
+            if let Err(e) = ChatService::typing(pool, srv, user_id, conversation_id, group_id, is_typing, Some(client)).await {
+                log::debug!("Dropped typing indicator from user {}: {}", user_id, e);
+            }
+        },
+        WsMessage::MessageRead { message_id } => {
+            if let Err(e) = ChatService::mark_message_read(pool, srv, user_id, message_id).await {
+                log::warn!("Failed to mark message {} read: {}", message_id, e);
+                reply(out, &ServerEvent::error(ErrorCode::ReadRejected, e.to_string()));
+            }
+        },
+        WsMessage::ConversationRead { conversation_id, up_to_message_id } => {
+            if let Err(e) = ChatService::mark_conversation_read(pool, srv, user_id, conversation_id, up_to_message_id).await {
+                log::warn!("Failed to mark conversation {} read: {}", conversation_id, e);
+                reply(out, &ServerEvent::error(ErrorCode::ReadRejected, e.to_string()));
+            }
+        },
+        WsMessage::GroupRead { group_id, up_to_message_id } => {
+            if let Err(e) = ChatService::mark_group_read(pool, srv, user_id, group_id, up_to_message_id).await {
+                log::warn!("Failed to mark group {} read: {}", group_id, e);
+                reply(out, &ServerEvent::error(ErrorCode::ReadRejected, e.to_string()));
+            }
+        },
+        WsMessage::EditMessage { message_id, group_id, content } => {
+            // The MessageEdited broadcast also reaches this connection
+            if let Err(e) = ChatService::edit_message(pool, srv, user_id, message_id, group_id, &content).await {
+                log::warn!("Failed to edit message {}: {}", message_id, e);
+                reply(out, &ServerEvent::error(ErrorCode::EditRejected, e.to_string()));
+            }
+        },
+        WsMessage::DeleteMessage { message_id, group_id, for_everyone } => {
+            if let Err(e) = ChatService::delete_message(pool, srv, user_id, message_id, group_id, for_everyone).await {
+                log::warn!("Failed to delete message {}: {}", message_id, e);
+                reply(out, &ServerEvent::error(ErrorCode::DeleteRejected, e.to_string()));
+            }
+        },
+        WsMessage::Sync { since } => {
+            let since = since.unwrap_or(0).max(0);
+            match SyncRepository::get_events(pool, user_id, since, SYNC_BATCH_SIZE).await {
+                Ok(batch) => {
+                    // Direct messages in this page have now reached a device
+                    let message_ids = batch.direct_message_ids();
+                    if let Err(e) = ChatService::confirm_delivery(pool, srv, user_id, &message_ids).await {
+                        log::error!("Failed to mark synced messages delivered for {}: {}", user_id, e);
+                    }
+
+                    let event = ServerEvent::SyncBatch {
+                        events: batch.events,
+                        cursor: batch.cursor,
+                        has_more: batch.has_more,
+                        resync_required: batch.resync_required,
+                    };
+                    reply(out, &event);
+                },
+                Err(e) => {
+                    log::error!("Failed to sync user {}: {}", user_id, e);
+                    reply(out, &ServerEvent::error(ErrorCode::SyncFailed, "Failed to sync"));
+                }
+            }
+        },
+        WsMessage::UserStatus { status } => {
+            if let Err(e) = presence.set_status(pool, srv, user_id, status).await {
+                reply(out, &ServerEvent::error(ErrorCode::StatusRejected, e.to_string()));
+            }
+        },
+    }
+}
+
+/// Send an event to this connection only
+fn reply(out: &OutboundQueue, event: &ServerEvent) {
+    out.push(QueuedEvent::new(event));
+}
+
+/// Error frame for a rejected send; database errors are not passed on
+fn send_error(e: Box<dyn std::error::Error>) -> ServerEvent {
+    let msg = e.to_string();
+    if msg.contains("limited to") {
+        ServerEvent::error(ErrorCode::ContentTooLarge, msg)
+    } else if msg.contains("empty") || msg.contains("yourself") || msg.contains("client_msg_id")
+        || msg.contains("not found") || msg.contains("not a member")
+    {
+        ServerEvent::error(ErrorCode::SendFailed, msg)
+    } else {
+        log::error!("Failed to save message: {}", e);
+        ServerEvent::error(ErrorCode::SendFailed, "Failed to send message")
+    }
+}
+
+// Helpers to pull credentials out of the handshake request
+mod handshake {
+    use actix_web::http::header::{AUTHORIZATION, SEC_WEBSOCKET_PROTOCOL};
+    use actix_web::HttpRequest;
+    use crate::modules::ws::type_def::Codec;
+
+    /// Prefix of the protocol subprotocols (`chat.v1`, `chat.v2+msgpack`, ...)
+    const VERSION_PREFIX: &str = "chat.";
+
+    /// Subprotocol marker; the token is sent as the next offered protocol:
+    /// `Sec-WebSocket-Protocol: bearer, <jwt>`
+    pub const BEARER_PROTOCOL: &str = "bearer";
+
+    pub enum Credential {
+        /// JWT from the `Authorization: Bearer` header
+        Bearer(String),
+        /// JWT passed through `Sec-WebSocket-Protocol` (browsers can't set headers)
+        Subprotocol(String),
+        /// Single-use ticket from `POST /api/auth/ws-ticket`, sent as `?ticket=`
+        Ticket(String),
+    }
+
+    pub fn extract_credential(req: &HttpRequest) -> Option<Credential> {
+        if let Some(token) = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|s| s.strip_prefix("Bearer "))
+        {
+            return Some(Credential::Bearer(token.to_string()));
+        }
+
+        if let Some(protocols) = req.headers().get(SEC_WEBSOCKET_PROTOCOL).and_then(|h| h.to_str().ok()) {
+            let mut offered = protocols.split(',').map(str::trim);
+            if offered.any(|p| p == BEARER_PROTOCOL) {
+                if let Some(token) = offered.next().filter(|t| !t.is_empty()) {
+                    return Some(Credential::Subprotocol(token.to_string()));
+                }
+            }
+        }
+
+        extract_ticket_from_query(req.query_string()).map(Credential::Ticket)
+    }
+
+    fn extract_ticket_from_query(query: &str) -> Option<String> {
         let params: Vec<(String, String)> = serde_urlencoded::from_str(query).ok()?;
         for (k, v) in params {
-            if k == "userId" {
-                return v.parse::<i32>().ok();
+            if k == "ticket" && !v.is_empty() {
+                return Some(v);
             }
         }
         None
     }
+
+    /// The protocol the client offered with the newest version (the first offered
+    /// wins between formats of the same version), or None if it didn't ask for one.
+    /// Fails if it only offered protocols this server doesn't speak.
+    pub fn requested_codec(req: &HttpRequest) -> Result<Option<Codec>, String> {
+        let protocols = match req.headers().get(SEC_WEBSOCKET_PROTOCOL).and_then(|h| h.to_str().ok()) {
+            Some(protocols) => protocols,
+            None => return Ok(None),
+        };
+
+        let offered: Vec<&str> = protocols
+            .split(',')
+            .map(str::trim)
+            .filter(|p| p.starts_with(VERSION_PREFIX))
+            .collect();
+        if offered.is_empty() {
+            return Ok(None);
+        }
+
+        let mut chosen: Option<Codec> = None;
+        for codec in offered.iter().filter_map(|p| Codec::from_name(p)) {
+            if chosen.is_none_or(|best| codec.version > best.version) {
+                chosen = Some(codec);
+            }
+        }
+
+        match chosen {
+            Some(codec) => Ok(Some(codec)),
+            None => Err(format!("Unsupported protocol; supported: {}", Codec::supported().join(", "))),
+        }
+    }
 }
 
 /// Configure WS routes
@@ -220,3 +456,44 @@ pub fn configure(cfg: &mut web::ServiceConfig) {
         web::resource("/ws").route(web::get().to(start_connection))
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::SEC_WEBSOCKET_PROTOCOL;
+    use actix_web::test::TestRequest;
+    use super::handshake::requested_codec;
+    use crate::modules::ws::type_def::{Codec, ProtocolVersion, WireFormat};
+
+    fn offering(protocols: &str) -> Result<Option<Codec>, String> {
+        requested_codec(&TestRequest::default().insert_header((SEC_WEBSOCKET_PROTOCOL, protocols)).to_http_request())
+    }
+
+    #[test]
+    fn no_protocol_offered_uses_the_default() {
+        assert_eq!(requested_codec(&TestRequest::default().to_http_request()), Ok(None));
+        assert_eq!(offering("bearer, some.jwt"), Ok(None));
+    }
+
+    #[test]
+    fn newest_offered_version_wins() {
+        let codec = offering("chat.v1, chat.v2+cbor").unwrap().unwrap();
+        assert_eq!((codec.version, codec.format), (ProtocolVersion::V2, WireFormat::Cbor));
+    }
+
+    #[test]
+    fn first_offered_format_wins_within_a_version() {
+        let codec = offering("chat.v2+msgpack, chat.v2").unwrap().unwrap();
+        assert_eq!((codec.version, codec.format), (ProtocolVersion::V2, WireFormat::MessagePack));
+    }
+
+    #[test]
+    fn unknown_protocols_are_skipped() {
+        assert_eq!(offering("chat.v9, bearer, chat.v1+yaml, chat.v1"), Ok(Some(Codec::DEFAULT)));
+    }
+
+    #[test]
+    fn only_unknown_protocols_are_refused() {
+        let err = offering("chat.v9, chat.v2+yaml").unwrap_err();
+        assert!(err.contains("chat.v2+msgpack"), "{}", err);
+    }
+}
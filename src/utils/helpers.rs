@@ -2,6 +2,7 @@ use bcrypt::{hash, verify, DEFAULT_COST};
 use chrono::{Duration, Utc};
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::env;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -10,6 +11,7 @@ pub struct Claims {
     pub email: String,
     pub exp: i64, // expiration timestamp
     pub iat: i64, // issued at
+    pub jti: String, // unique token id, so every issued token hashes differently
 }
 
 /// Hash a password using bcrypt
@@ -22,22 +24,35 @@ pub fn verify_password(password: &str, hash: &str) -> Result<bool, bcrypt::Bcryp
     verify(password, hash)
 }
 
+/// Access token lifetime in seconds (JWT_EXPIRATION)
+pub fn jwt_expiration() -> i64 {
+    env::var("JWT_EXPIRATION")
+        .unwrap_or_else(|_| "3600".to_string())
+        .parse::<i64>()
+        .unwrap_or(3600)
+}
+
+/// Refresh token lifetime in seconds (REFRESH_TOKEN_EXPIRATION)
+pub fn refresh_token_expiration() -> i64 {
+    env::var("REFRESH_TOKEN_EXPIRATION")
+        .unwrap_or_else(|_| "2592000".to_string())
+        .parse::<i64>()
+        .unwrap_or(2592000)
+}
+
 /// Generate JWT token
 pub fn generate_jwt(user_id: i32, email: &str) -> Result<String, jsonwebtoken::errors::Error> {
     let secret = env::var("JWT_SECRET").expect("JWT_SECRET must be set");
-    let expiration = env::var("JWT_EXPIRATION")
-        .unwrap_or_else(|_| "3600".to_string())
-        .parse::<i64>()
-        .unwrap_or(3600);
 
     let now = Utc::now();
-    let exp = (now + Duration::seconds(expiration)).timestamp();
+    let exp = (now + Duration::seconds(jwt_expiration())).timestamp();
 
     let claims = Claims {
         sub: user_id,
         email: email.to_string(),
         exp,
         iat: now.timestamp(),
+        jti: uuid::Uuid::new_v4().to_string(),
     };
 
     encode(
@@ -61,6 +76,12 @@ pub fn verify_jwt(token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
     Ok(token_data.claims)
 }
 
+/// Hash a token with SHA-256 (hex encoded).
+/// Unlike bcrypt this is deterministic, so the hash can be used to look tokens up.
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
 /// Generate a simple random string for refresh tokens
 pub fn generate_refresh_token() -> String {
     use uuid::Uuid;